@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE locations DROP COLUMN altitude;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE locations ADD COLUMN altitude REAL;
\ No newline at end of file
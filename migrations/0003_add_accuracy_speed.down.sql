@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE locations DROP COLUMN speed;
+ALTER TABLE locations DROP COLUMN accuracy;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE locations ADD COLUMN accuracy REAL;
+ALTER TABLE locations ADD COLUMN speed REAL;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE locations DROP COLUMN provider;
+ALTER TABLE locations DROP COLUMN battery;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE locations ADD COLUMN battery REAL;
+ALTER TABLE locations ADD COLUMN provider TEXT;
\ No newline at end of file
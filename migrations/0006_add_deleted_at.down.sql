@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE locations DROP COLUMN deleted_at;
\ No newline at end of file
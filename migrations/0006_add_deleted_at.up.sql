@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE locations ADD COLUMN deleted_at TIMESTAMP;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE locations ADD COLUMN tags TEXT;
\ No newline at end of file
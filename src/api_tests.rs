@@ -0,0 +1,1003 @@
+//! End-to-end tests of the HTTP API, over an in-memory database.
+
+use axum::body::{self, Body};
+use axum::http::{Request, StatusCode};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use serde_json::{json, Value};
+use sqlx::sqlite::SqlitePoolOptions;
+use tower::ServiceExt;
+use tower_http::normalize_path::NormalizePath;
+
+use super::*;
+
+/// Build the state over a fresh in-memory database, with the default configuration.
+async fn test_state() -> App {
+    // Every connection to `sqlite::memory:` opens its own database, so keep a single one open
+    let database_pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&database_pool).await.unwrap();
+
+    App {
+        database_pool,
+        api_key: None,
+        metrics: PrometheusBuilder::new().build_recorder().handle(),
+        default_page_size: DEFAULT_PAGE_SIZE,
+        max_page_size: DEFAULT_MAX_PAGE_SIZE,
+        max_results: DEFAULT_MAX_RESULTS,
+        updates: broadcast::channel(UPDATES_CAPACITY).0,
+        rate_limiter: None,
+        dedup: None,
+        sources_cache: Arc::new(TtlCache::new(Duration::ZERO)),
+        count_cache: Arc::new(TtlCache::new(Duration::ZERO)),
+        idempotency_ttl: TimeDelta::seconds(DEFAULT_IDEMPOTENCY_TTL_SECS),
+        geocoder: None,
+        base_path: String::new(),
+        read_only: false,
+        coord_precision: None,
+        stale_after: TimeDelta::seconds(DEFAULT_STALE_AFTER_SECS),
+        field_case: FieldCase::Snake,
+        default_source: None,
+        max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+        quality: QualityThresholds { good_m: DEFAULT_QUALITY_GOOD_M, fair_m: DEFAULT_QUALITY_FAIR_M },
+        debug_config: None,
+    }
+}
+
+/// Build the router over `state`.
+fn router_with(state: App) -> NormalizePath<Router> {
+    let request_timeout = Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS);
+    router(state, cors_layer("").unwrap(), DEFAULT_MAX_BODY_BYTES, DEFAULT_MAX_BATCH_BODY_BYTES, request_timeout)
+}
+
+/// Build the router over a fresh in-memory database, with the default configuration.
+async fn test_router() -> NormalizePath<Router> {
+    router_with(test_state().await)
+}
+
+/// Send a request to the router, returning the status and JSON body of the response.
+async fn send(router: &NormalizePath<Router>, request: Request<Body>) -> (StatusCode, Value) {
+    let response = router.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body = if bytes.is_empty() { Value::Null } else { serde_json::from_slice(&bytes).unwrap() };
+    (status, body)
+}
+
+async fn get(router: &NormalizePath<Router>, uri: &str) -> (StatusCode, Value) {
+    send(router, Request::get(uri).body(Body::empty()).unwrap()).await
+}
+
+async fn post(router: &NormalizePath<Router>, uri: &str, body: Value) -> (StatusCode, Value) {
+    let request = Request::post(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    send(router, request).await
+}
+
+#[tokio::test]
+async fn post_then_get_returns_the_record() {
+    let router = test_router().await;
+
+    let (status, created) = post(&router, "/", json!({ "source": "phone", "latitude": 48.85, "longitude": 2.35 })).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["id"], 1);
+    assert_eq!(created["source"], "phone");
+    assert_eq!(created["latitude"], 48.85);
+
+    // Records are listed with the quality derived from their accuracy
+    let mut expected = created.clone();
+    expected["quality"] = json!("unknown");
+    let (status, records) = get(&router, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(records, json!([expected]));
+}
+
+#[tokio::test]
+async fn post_stores_device_metadata() {
+    let router = test_router().await;
+
+    let body = json!({ "source": "phone", "latitude": 0, "longitude": 0, "battery": 82.5, "provider": " gps " });
+    let (status, created) = post(&router, "/", body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["battery"], 82.5);
+    assert_eq!(created["provider"], "gps");
+
+    let (_, created) = post(&router, "/", json!({ "source": "phone", "latitude": 0, "longitude": 0 })).await;
+    assert_eq!(created["battery"], Value::Null);
+    assert_eq!(created["provider"], Value::Null);
+
+    let body = json!({ "source": "phone", "latitude": 0, "longitude": 0, "provider": "gps;" });
+    let (status, error) = post(&router, "/", body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_parameter");
+
+    let body = json!({ "source": "phone", "latitude": 0, "longitude": 0, "battery": 101 });
+    let (status, _) = post(&router, "/", body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn post_accepts_arrays_as_a_batch() {
+    let router = test_router().await;
+
+    let body = json!([
+        { "source": "phone", "latitude": 1, "longitude": 2 },
+        { "source": "watch", "latitude": 3, "longitude": 4 },
+    ]);
+    let (status, created) = post(&router, "/", body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created[0]["source"], "phone");
+    assert_eq!(created[1]["source"], "watch");
+
+    // A single invalid record rejects the whole array
+    let body = json!([
+        { "source": "phone", "latitude": 1, "longitude": 2 },
+        { "source": "watch", "latitude": 91, "longitude": 4 },
+    ]);
+    let (status, error) = post(&router, "/", body).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_coordinates");
+
+    // Records are listed with the quality derived from their accuracy
+    let mut expected = created.clone();
+    for record in expected.as_array_mut().unwrap() {
+        record["quality"] = json!("unknown");
+    }
+    let (_, records) = get(&router, "/").await;
+    assert_eq!(records, expected);
+}
+
+#[tokio::test]
+async fn post_rejects_invalid_sources() {
+    let router = test_router().await;
+
+    let (status, error) = post(&router, "/", json!({ "source": "a;b", "latitude": 0, "longitude": 0 })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_source");
+
+    let (status, error) = get(&router, "/?source=a%3Bb").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_source");
+
+    let (_, records) = get(&router, "/").await;
+    assert_eq!(records, json!([]));
+}
+
+#[tokio::test]
+async fn get_combines_filters() {
+    let router = test_router().await;
+    for (source, latitude, created_at) in [
+        ("phone", 10.0, "2024-05-01T10:00:00Z"),
+        ("phone", 20.0, "2024-05-02T10:00:00Z"),
+        ("watch", 10.0, "2024-05-01T12:00:00Z"),
+        ("car", 10.0, "2024-05-03T10:00:00Z"),
+    ] {
+        let body = json!({ "source": source, "latitude": latitude, "longitude": 0, "created_at": created_at });
+        let (status, _) = post(&router, "/", body).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    let ids = |records: Value| -> Vec<i64> {
+        records.as_array().unwrap().iter().map(|r| r["id"].as_i64().unwrap()).collect()
+    };
+
+    let (_, records) = get(&router, "/?source=phone").await;
+    assert_eq!(ids(records), [1, 2]);
+
+    let (_, records) = get(&router, "/?source=phone,watch&order=desc").await;
+    assert_eq!(ids(records), [2, 3, 1]);
+
+    let (_, records) = get(&router, "/?from=2024-05-01T11:00:00Z&to=2024-05-02T12:00:00%2B02:00").await;
+    assert_eq!(ids(records), [3, 2]);
+
+    let (_, records) = get(&router, "/?date=2024-05-01").await;
+    assert_eq!(ids(records), [1, 3]);
+
+    let (_, records) = get(&router, "/?source=phone,car&min_lat=5&max_lat=15&min_lon=-1&max_lon=1").await;
+    assert_eq!(ids(records), [1, 4]);
+
+    let (_, records) = get(&router, "/?limit=2&offset=1").await;
+    assert_eq!(ids(records), [3, 2]);
+
+    let (_, records) = get(&router, "/?source=phone,watch&last=2").await;
+    assert_eq!(ids(records), [2, 3]);
+
+    let (status, count) = get(&router, "/count?source=phone").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(count, json!({ "count": 2 }));
+}
+
+#[tokio::test]
+async fn get_rejects_invalid_filters() {
+    let router = test_router().await;
+
+    let (status, error) = get(&router, "/?min_lat=0").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_parameter");
+
+    let (status, error) = get(&router, "/?from=2024-05-02T00:00:00Z&to=2024-05-01T00:00:00Z").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_timestamp");
+
+    let (status, error) = get(&router, "/?date=2024-05-01&from=2024-05-01T00:00:00Z").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_parameter");
+
+    let (status, error) = get(&router, "/?from=2024-05-01").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_timestamp");
+
+    let (status, error) = get(&router, "/?limit=many").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_parameter");
+
+    let (status, error) = get(&router, "/?last=5&offset=10").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_parameter");
+}
+
+#[tokio::test]
+async fn heatmap_counts_records_per_cell() {
+    let router = test_router().await;
+    for (latitude, longitude) in [(48.8571, 2.3521), (48.8574, 2.3519), (48.8612, 2.3521), (48.8569, 2.3524)] {
+        let (status, _) = post(&router, "/", json!({ "source": "phone", "latitude": latitude, "longitude": longitude })).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    let (status, cells) = get(&router, "/heatmap?precision=3").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(cells, json!([
+        { "lat": 48.857, "lon": 2.352, "count": 3 },
+        { "lat": 48.861, "lon": 2.352, "count": 1 },
+    ]));
+
+    let (status, error) = get(&router, "/heatmap?precision=7").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_parameter");
+}
+
+#[tokio::test]
+async fn get_honours_if_none_match() {
+    let router = test_router().await;
+    post(&router, "/", json!({ "source": "phone", "latitude": 0, "longitude": 0 })).await;
+
+    let conditional_get = |etag: String| Request::get("/").header("if-none-match", etag).body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+
+    let response = router.clone().oneshot(conditional_get(etag.clone())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()["etag"], etag.as_str());
+
+    // A new matching record changes the tag
+    post(&router, "/", json!({ "source": "phone", "latitude": 1, "longitude": 1 })).await;
+    let response = router.clone().oneshot(conditional_get(etag.clone())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers()["etag"], etag.as_str());
+}
+
+#[tokio::test]
+async fn read_only_mode_forbids_writes() {
+    let router = router_with(App { read_only: true, ..test_state().await });
+
+    let (status, error) = post(&router, "/", json!({ "source": "phone", "latitude": 0, "longitude": 0 })).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(error["code"], "read_only");
+
+    let (status, error) = post(&router, "/batch", json!([])).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(error["code"], "read_only");
+
+    let (status, records) = get(&router, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(records, json!([]));
+}
+
+#[tokio::test]
+async fn trailing_slashes_are_ignored() {
+    let router = test_router().await;
+
+    let (status, count) = get(&router, "/count/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(count, json!({ "count": 0 }));
+
+    let (status, records) = get(&router, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(records, json!([]));
+}
+
+#[tokio::test]
+async fn delete_source_requires_confirmation() {
+    let router = test_router().await;
+    for source in ["phone", "phone", "watch"] {
+        post(&router, "/", json!({ "source": source, "latitude": 0, "longitude": 0 })).await;
+    }
+    let delete = |uri: &str| Request::delete(uri).body(Body::empty()).unwrap();
+
+    let (status, error) = send(&router, delete("/sources/phone")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_parameter");
+
+    let (status, deleted) = send(&router, delete("/sources/phone?confirm=true")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(deleted, json!({ "deleted": 2 }));
+
+    let (_, count) = get(&router, "/count").await;
+    assert_eq!(count, json!({ "count": 1 }));
+}
+
+#[tokio::test]
+async fn coordinates_are_rounded_to_the_configured_precision() {
+    let router = router_with(App { coord_precision: Some(4), ..test_state().await });
+
+    let (_, created) = post(&router, "/", json!({ "source": "phone", "latitude": 48.858370123, "longitude": 2.294481987 })).await;
+    assert_eq!(created["latitude"], 48.8584);
+    assert_eq!(created["longitude"], 2.2945);
+
+    let (_, created) = post(&router, "/", json!([{ "source": "phone", "latitude": -1.23456, "longitude": 0.00004 }])).await;
+    assert_eq!(created[0]["latitude"], -1.2346);
+    assert_eq!(created[0]["longitude"], 0.0);
+}
+
+#[tokio::test]
+async fn get_wraps_pages_in_an_envelope() {
+    let router = test_router().await;
+    for latitude in [1, 2, 3] {
+        post(&router, "/", json!({ "source": "phone", "latitude": latitude, "longitude": 0 })).await;
+    }
+
+    let (status, page) = get(&router, "/?paginated=true&limit=2").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(page["data"].as_array().unwrap().len(), 2);
+    assert_eq!(page["page"], json!({ "limit": 2, "offset": 0, "total": 3, "has_more": true }));
+
+    let (_, page) = get(&router, "/?paginated=true&limit=2&offset=2").await;
+    assert_eq!(page["data"][0]["latitude"], 3.0);
+    assert_eq!(page["page"], json!({ "limit": 2, "offset": 2, "total": 3, "has_more": false }));
+
+    let (status, _) = get(&router, "/?paginated=true&format=csv").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn deleted_records_are_kept_until_purged() {
+    let router = test_router().await;
+    post(&router, "/", json!({ "source": "phone", "latitude": 0, "longitude": 0 })).await;
+    let delete = |uri: &str| Request::delete(uri).body(Body::empty()).unwrap();
+
+    let (status, _) = send(&router, delete("/locations/1")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = get(&router, "/locations/1").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, records) = get(&router, "/").await;
+    assert_eq!(records, json!([]));
+
+    let (status, record) = get(&router, "/locations/1?include_deleted=true").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(record["deleted_at"].is_string());
+    let (_, records) = get(&router, "/?include_deleted=true").await;
+    assert_eq!(records.as_array().unwrap().len(), 1);
+
+    let (status, purged) = send(&router, delete("/purge")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(purged, json!({ "purged": 1 }));
+    let (_, records) = get(&router, "/?include_deleted=true").await;
+    assert_eq!(records, json!([]));
+}
+
+#[tokio::test]
+async fn nearby_record_excludes_the_anchor() {
+    let router = test_router().await;
+    for (latitude, longitude) in [(48.8566, 2.3522), (48.8606, 2.3376), (45.7640, 4.8357)] {
+        post(&router, "/", json!({ "source": "phone", "latitude": latitude, "longitude": longitude })).await;
+    }
+
+    let (status, nearby) = get(&router, "/locations/1/nearby?radius_km=5").await;
+    assert_eq!(status, StatusCode::OK);
+    let nearby = nearby.as_array().unwrap();
+    assert_eq!(nearby.len(), 1);
+    assert_eq!(nearby[0]["id"], 2);
+    assert!(nearby[0]["distance_km"].as_f64().unwrap() < 2.0);
+
+    let (status, _) = get(&router, "/locations/9/nearby?radius_km=5").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn query_runs_every_set_of_filters() {
+    let router = test_router().await;
+    for source in ["phone", "watch", "phone"] {
+        post(&router, "/", json!({ "source": source, "latitude": 0, "longitude": 0 })).await;
+    }
+
+    let body = json!([{ "source": "phone" }, { "source": "watch", "limit": 5 }, { "since_id": 2, "order": "desc" }]);
+    let (status, results) = post(&router, "/query", body).await;
+    assert_eq!(status, StatusCode::OK);
+    let ids: Vec<Vec<i64>> = results.as_array().unwrap().iter()
+        .map(|records| records.as_array().unwrap().iter().map(|r| r["id"].as_i64().unwrap()).collect())
+        .collect();
+    assert_eq!(ids, [vec![1, 3], vec![2], vec![3]]);
+
+    // Flags reproducing the default behaviour are harmless, unlike those enabling it
+    let (status, _) = post(&router, "/query", json!([{ "enrich": false, "paginated": false }])).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = post(&router, "/query", json!([{ "paginated": true }])).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, error) = post(&router, "/query", json!([{}, { "source": "a;b" }])).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_source");
+
+    let (status, _) = post(&router, "/query", json!(vec![json!({}); MAX_QUERIES + 1])).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn unknown_routes_and_methods_return_json_errors() {
+    let router = test_router().await;
+
+    let (status, error) = get(&router, "/nowhere").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(error, json!({ "error": "not found", "code": "route_not_found" }));
+
+    let (status, error) = post(&router, "/count", json!({})).await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(error["code"], "method_not_allowed");
+}
+
+#[tokio::test]
+async fn gzipped_batches_are_decompressed() {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let router = test_router().await;
+    let gzipped_post = |uri: &str, body: &[u8]| {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        Request::post(uri)
+            .header("content-type", "application/json")
+            .header("content-encoding", "gzip")
+            .body(Body::from(encoder.finish().unwrap()))
+            .unwrap()
+    };
+
+    let body = json!([{ "source": "phone", "latitude": 0, "longitude": 0 }]).to_string();
+    let (status, inserted) = send(&router, gzipped_post("/batch", body.as_bytes())).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(inserted, json!({ "inserted": 1 }));
+
+    // The limit applies to the decompressed body, however small the compressed one
+    let padding = " ".repeat(DEFAULT_MAX_BATCH_BODY_BYTES);
+    let response = router.clone().oneshot(gzipped_post("/batch", format!("[]{}", padding).as_bytes())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn sources_status_flags_stale_sources() {
+    let router = test_router().await;
+    let recent = timestamp::format(&(Utc::now().naive_utc() - TimeDelta::minutes(5)));
+    let old = timestamp::format(&(Utc::now().naive_utc() - TimeDelta::days(1)));
+    for (source, created_at) in [("phone", &old), ("phone", &recent), ("watch", &old)] {
+        post(&router, "/", json!({ "source": source, "latitude": 0, "longitude": 0, "created_at": created_at })).await;
+    }
+
+    let (status, statuses) = get(&router, "/sources/status").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(statuses[0]["source"], "phone");
+    assert_eq!(statuses[0]["stale"], false);
+    assert!(statuses[0]["age_secs"].as_i64().unwrap() >= 300);
+    assert_eq!(statuses[1]["source"], "watch");
+    assert_eq!(statuses[1]["stale"], true);
+}
+
+#[tokio::test]
+async fn json_fields_can_be_camel_cased() {
+    let router = router_with(App { field_case: FieldCase::Camel, ..test_state().await });
+
+    let (status, created) = post(&router, "/", json!({ "source": "phone", "latitude": 0, "longitude": 0 })).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert!(created["createdAt"].is_string());
+    assert!(created.get("created_at").is_none());
+
+    let (_, page) = get(&router, "/?paginated=true").await;
+    assert_eq!(page["page"]["hasMore"], false);
+}
+
+#[tokio::test]
+async fn import_inserts_valid_points_and_reports_the_others() {
+    let router = test_router().await;
+
+    let csv = "latitude,longitude,created_at\n10.0,20.0,2024-01-02T03:04:05Z\n95.0,20.0,\n11.0,21.0,\n";
+    let body = format!(
+        "--X\r\nContent-Disposition: form-data; name=\"source\"\r\n\r\nlogger\r\n\
+        --X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"log.csv\"\r\nContent-Type: text/csv\r\n\r\n{}\r\n\
+        --X--\r\n",
+        csv,
+    );
+    let request = Request::post("/import")
+        .header("content-type", "multipart/form-data; boundary=X")
+        .body(Body::from(body))
+        .unwrap();
+    let (status, report) = send(&router, request).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(report["imported"], 2);
+    assert_eq!(report["skipped"][0]["index"], 1);
+
+    let (_, records) = get(&router, "/?source=logger").await;
+    assert_eq!(records.as_array().unwrap().len(), 2);
+    assert_eq!(records[0]["created_at"], "2024-01-02T03:04:05Z");
+}
+
+#[tokio::test]
+async fn import_is_not_limited_to_the_batch_size() {
+    let router = test_router().await;
+
+    let points = DEFAULT_MAX_BATCH_SIZE + 1;
+    let csv: String = std::iter::once("latitude,longitude\n".to_string())
+        .chain((0..points).map(|i| format!("{},0\n", i % 90)))
+        .collect();
+    let body = format!(
+        "--X\r\nContent-Disposition: form-data; name=\"source\"\r\n\r\nlogger\r\n\
+        --X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"log.csv\"\r\nContent-Type: text/csv\r\n\r\n{}\r\n\
+        --X--\r\n",
+        csv,
+    );
+    let request = Request::post("/import")
+        .header("content-type", "multipart/form-data; boundary=X")
+        .body(Body::from(body))
+        .unwrap();
+    let (status, report) = send(&router, request).await;
+    assert_eq!(status, StatusCode::CREATED, "{:?}", report);
+    assert_eq!(report["imported"], points);
+
+    let (_, count) = get(&router, "/count").await;
+    assert_eq!(count["count"], points);
+}
+
+#[tokio::test]
+async fn records_without_source_fall_back_to_the_default() {
+    let (status, error) = post(&test_router().await, "/", json!({ "latitude": 0, "longitude": 0 })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_source");
+
+    let router = router_with(App { default_source: Some("anonymous".to_string()), ..test_state().await });
+    let (status, created) = post(&router, "/", json!({ "latitude": 0, "longitude": 0 })).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["source"], "anonymous");
+
+    let (status, created) = post(&router, "/", json!({ "source": "", "latitude": 0, "longitude": 0 })).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["source"], "anonymous");
+}
+
+/// Build the state over a fresh database file named after `name`, with `connections` connections
+/// failing as soon as the database is locked, instead of waiting.
+async fn file_state(name: &str, connections: u32) -> (App, std::path::PathBuf) {
+    let path = std::env::temp_dir().join(format!("ntb_location_{}_{}.db", name, std::process::id()));
+    remove_database(&path);
+    let options = SqliteConnectOptions::new()
+        .filename(&path)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(Duration::ZERO);
+    let database_pool = SqlitePoolOptions::new()
+        .max_connections(connections)
+        .connect_with(options)
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&database_pool).await.unwrap();
+    (App { database_pool, ..test_state().await }, path)
+}
+
+/// Remove the database file at `path` and its WAL files.
+fn remove_database(path: &std::path::Path) {
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn inserts_wait_for_the_database_to_be_unlocked() {
+    let (state, path) = file_state("locked", 2).await;
+    let router = router_with(state.clone());
+
+    // Hold the write lock for a while from another connection
+    let mut lock = state.database_pool.acquire().await.unwrap();
+    sqlx::query("BEGIN IMMEDIATE").execute(&mut *lock).await.unwrap();
+    let release = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        sqlx::query("COMMIT").execute(&mut *lock).await.unwrap();
+    });
+
+    let (status, _) = post(&router, "/", json!({ "source": "phone", "latitude": 0, "longitude": 0 })).await;
+    assert_eq!(status, StatusCode::CREATED);
+    release.await.unwrap();
+
+    state.database_pool.close().await;
+    remove_database(&path);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_writers_all_succeed() {
+    let (state, path) = file_state("concurrent", 8).await;
+    let router = router_with(state.clone());
+
+    let mut writers = JoinSet::new();
+    for writer in 0..8 {
+        let single_router = router.clone();
+        writers.spawn(async move {
+            for i in 0..10 {
+                let record = json!({ "source": format!("writer-{}", writer), "latitude": i, "longitude": 0 });
+                let (status, _) = post(&single_router, "/", record).await;
+                assert_eq!(status, StatusCode::CREATED);
+            }
+        });
+        let batch = json!([{ "source": "batch", "latitude": writer, "longitude": 0 }]);
+        let batch_router = router.clone();
+        writers.spawn(async move {
+            let (status, _) = post(&batch_router, "/batch", batch).await;
+            assert_eq!(status, StatusCode::CREATED);
+        });
+    }
+    while let Some(result) = writers.join_next().await {
+        result.unwrap();
+    }
+
+    let (_, count) = get(&router, "/count").await;
+    assert_eq!(count["count"], 88);
+
+    state.database_pool.close().await;
+    remove_database(&path);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_retries_with_the_same_idempotency_key_insert_once() {
+    let (state, path) = file_state("idempotent", 8).await;
+    let router = router_with(state.clone());
+
+    let mut retries = JoinSet::new();
+    for _ in 0..8 {
+        let router = router.clone();
+        retries.spawn(async move {
+            let request = Request::post("/")
+                .header("content-type", "application/json")
+                .header("idempotency-key", "retry-1")
+                .body(Body::from(json!({ "source": "phone", "latitude": 1, "longitude": 2 }).to_string()))
+                .unwrap();
+            let (status, created) = send(&router, request).await;
+            assert_eq!(status, StatusCode::CREATED, "{:?}", created);
+            created["id"].clone()
+        });
+    }
+    let mut ids = Vec::new();
+    while let Some(result) = retries.join_next().await {
+        ids.push(result.unwrap());
+    }
+    assert!(ids.iter().all(|id| *id == ids[0]), "{:?}", ids);
+
+    let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM locations").fetch_one(&state.database_pool).await.unwrap();
+    assert_eq!(rows, 1);
+
+    state.database_pool.close().await;
+    remove_database(&path);
+}
+
+#[tokio::test]
+async fn render_returns_a_png_of_the_requested_size() {
+    let router = test_router().await;
+    post(&router, "/", json!({ "source": "phone", "latitude": 48.85, "longitude": 2.35 })).await;
+    post(&router, "/", json!({ "source": "phone", "latitude": 48.86, "longitude": 2.36 })).await;
+
+    let response = router.clone().oneshot(Request::get("/render.png?width=200&height=100").body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let info = png::Decoder::new(std::io::Cursor::new(bytes)).read_info().unwrap().info().clone();
+    assert_eq!((info.width, info.height), (200, 100));
+
+    let (status, error) = get(&router, "/render.png?width=0").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_parameter");
+}
+
+#[tokio::test]
+async fn batches_must_be_neither_empty_nor_too_large() {
+    let router = router_with(App { max_batch_size: 2, ..test_state().await });
+
+    let (status, error) = post(&router, "/batch", json!([])).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_parameter");
+
+    let record = json!({ "source": "phone", "latitude": 0, "longitude": 0 });
+    let (status, error) = post(&router, "/batch", json!([record, record, record])).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(error["code"], "batch_too_large");
+
+    let (status, _) = post(&router, "/batch", json!([record, record])).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn fields_selects_the_returned_fields() {
+    let router = test_router().await;
+    post(&router, "/", json!({ "source": "phone", "latitude": 1.5, "longitude": 2.5 })).await;
+
+    let (status, records) = get(&router, "/?fields=id,latitude,longitude").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(records, json!([{ "id": 1, "latitude": 1.5, "longitude": 2.5 }]));
+
+    let (status, error) = get(&router, "/?fields=id,password").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_parameter");
+
+    let (status, _) = get(&router, "/?fields=id&format=csv").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn records_can_be_tagged_and_filtered_by_tag() {
+    let router = test_router().await;
+
+    let (status, created) = post(&router, "/", json!({ "source": "phone", "latitude": 0, "longitude": 0, "tags": " hike, ,morning " })).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["tags"], "hike,morning");
+    post(&router, "/", json!({ "source": "phone", "latitude": 1, "longitude": 1, "tags": "hiker" })).await;
+    post(&router, "/", json!({ "source": "phone", "latitude": 2, "longitude": 2 })).await;
+
+    let (_, records) = get(&router, "/?tag=hike").await;
+    assert_eq!(records.as_array().unwrap().len(), 1);
+    assert_eq!(records[0]["id"], created["id"]);
+
+    let (status, error) = post(&router, "/", json!({ "source": "phone", "latitude": 0, "longitude": 0, "tags": "a;b" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_parameter");
+
+    let (status, _) = get(&router, "/?tag=a%25").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn polyline_format_encodes_the_track_of_each_source() {
+    let router = test_router().await;
+    for (lat, lon) in [(38.5, -120.2), (40.7, -120.95), (43.252, -126.453)] {
+        post(&router, "/", json!({ "source": "phone", "latitude": lat, "longitude": lon })).await;
+    }
+    post(&router, "/", json!({ "source": "watch", "latitude": 0, "longitude": 0 })).await;
+
+    let (status, tracks) = get(&router, "/?format=polyline").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(tracks, json!([
+        { "source": "phone", "points": 3, "polyline": "_p~iF~ps|U_ulLnnqC_mqNvxq`@" },
+        { "source": "watch", "points": 1, "polyline": "??" },
+    ]));
+}
+
+#[tokio::test]
+async fn rename_source_moves_records_unless_it_would_merge() {
+    let router = test_router().await;
+    for source in ["phone", "phone", "watch"] {
+        post(&router, "/", json!({ "source": source, "latitude": 0, "longitude": 0 })).await;
+    }
+
+    let (status, error) = post(&router, "/sources/phone/rename", json!({ "new": "watch" })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error["code"], "source_exists");
+
+    let (status, renamed) = post(&router, "/sources/phone/rename", json!({ "new": "pixel" })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(renamed, json!({ "renamed": 2 }));
+
+    let (status, renamed) = post(&router, "/sources/watch/rename", json!({ "new": "pixel", "merge": true })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(renamed, json!({ "renamed": 1 }));
+
+    let (_, records) = get(&router, "/?source=pixel").await;
+    assert_eq!(records.as_array().unwrap().len(), 3);
+
+    let (status, error) = post(&router, "/sources/pixel/rename", json!({ "new": "a;b" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_source");
+}
+
+#[tokio::test]
+async fn debug_config_is_only_served_when_enabled_and_authorised() {
+    let (status, _) = get(&test_router().await, "/debug/config").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let config = EffectiveConfig { port: 3000, api_key_set: true, ..EffectiveConfig::default() };
+    let state = App {
+        api_key: Some("secret".to_string()),
+        debug_config: Some(Arc::new(config)),
+        ..test_state().await
+    };
+    let router = router_with(state);
+
+    let (status, _) = get(&router, "/debug/config").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let request = Request::get("/debug/config").header("X-API-Key", "secret").body(Body::empty()).unwrap();
+    let (status, config) = send(&router, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(config["port"], 3000);
+    assert_eq!(config["api_key_set"], true);
+    assert!(!config.to_string().contains("secret"));
+}
+
+#[tokio::test]
+async fn records_are_rated_and_filtered_by_quality() {
+    let router = test_router().await;
+    for accuracy in [json!(5), json!(50), json!(500), json!(null)] {
+        post(&router, "/", json!({ "source": "phone", "latitude": 0, "longitude": 0, "accuracy": accuracy })).await;
+    }
+
+    let (_, records) = get(&router, "/").await;
+    let qualities: Vec<&str> = records.as_array().unwrap().iter().map(|r| r["quality"].as_str().unwrap()).collect();
+    assert_eq!(qualities, ["good", "fair", "poor", "unknown"]);
+
+    // NDJSON records have the same shape as JSON ones
+    let request = Request::get("/?format=ndjson").body(Body::empty()).unwrap();
+    let response = router.clone().oneshot(request).await.unwrap();
+    let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let lines: Vec<Value> = bytes.split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect();
+    assert_eq!(Value::Array(lines), records);
+
+    let (_, records) = get(&router, "/?min_quality=fair").await;
+    assert_eq!(records.as_array().unwrap().len(), 2);
+
+    let (_, count) = get(&router, "/count?min_quality=poor").await;
+    assert_eq!(count, json!({ "count": 3 }));
+
+    let (status, error) = get(&router, "/?min_quality=unknown").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["code"], "invalid_parameter");
+}
+
+#[tokio::test]
+async fn malformed_json_bodies_name_the_offending_field() {
+    let router = test_router().await;
+
+    let (status, error) = post(&router, "/", json!({ "source": "phone", "latitude": "north", "longitude": 0 })).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error["code"], "invalid_json");
+    assert_eq!(error["field"], "latitude");
+
+    let body = json!([{ "source": "phone", "latitude": 0, "longitude": 0 }, { "source": "phone", "latitude": 0 }]);
+    let (status, error) = post(&router, "/", body).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error["field"], "[1]");
+
+    let request = Request::post("/").header("content-type", "application/json").body(Body::from("{")).unwrap();
+    let (status, error) = send(&router, request).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error.get("field"), None);
+
+    let request = Request::post("/").body(Body::from("{}")).unwrap();
+    let (status, error) = send(&router, request).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(error["code"], "unsupported_media_type");
+}
+
+#[tokio::test]
+async fn bounds_cover_the_matching_records() {
+    let router = test_router().await;
+    for (source, latitude, longitude) in [("phone", 10.0, -5.0), ("phone", 20.0, 15.0), ("watch", 50.0, 50.0)] {
+        post(&router, "/", json!({ "source": source, "latitude": latitude, "longitude": longitude })).await;
+    }
+
+    let (status, bounds) = get(&router, "/bounds?source=phone").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        bounds,
+        json!({ "min_lat": 10.0, "max_lat": 20.0, "min_lon": -5.0, "max_lon": 15.0, "center_lat": 15.0, "center_lon": 5.0 }),
+    );
+
+    let (status, bounds) = get(&router, "/bounds?source=car").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(bounds["min_lat"], Value::Null);
+    assert_eq!(bounds["center_lon"], Value::Null);
+}
+
+/// Serve the router over `state` on a random local port, for clients needing a real connection.
+async fn serve(state: App) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = router_with(state);
+    tokio::spawn(async move {
+        axum::serve(listener, axum::ServiceExt::<Request<Body>>::into_make_service(app)).await.unwrap();
+    });
+    addr
+}
+
+#[tokio::test]
+async fn ws_subscribes_in_read_only_mode_but_rejects_inserts() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let state = App { read_only: true, ..test_state().await };
+    let updates = state.updates.clone();
+    let addr = serve(state).await;
+    let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+
+    // Records inserted elsewhere are still forwarded
+    let (_, created) = post(&test_router().await, "/", json!({ "source": "phone", "latitude": 1, "longitude": 2 })).await;
+    updates.send(serde_json::from_value(created.clone()).unwrap()).unwrap();
+    let Some(Ok(Message::Text(text))) = socket.next().await else { panic!("no record forwarded") };
+    assert_eq!(serde_json::from_str::<Value>(&text).unwrap(), created);
+
+    let record = json!({ "source": "phone", "latitude": 0, "longitude": 0 });
+    socket.send(Message::Text(record.to_string())).await.unwrap();
+    let Some(Ok(Message::Text(text))) = socket.next().await else { panic!("no error returned") };
+    assert_eq!(serde_json::from_str::<Value>(&text).unwrap()["code"], "read_only");
+}
+
+#[tokio::test]
+async fn ws_rejects_inserts_without_the_api_key() {
+    use futures_util::SinkExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    let addr = serve(App { api_key: Some("secret".to_string()), ..test_state().await }).await;
+    let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+
+    let record = json!({ "source": "phone", "latitude": 0, "longitude": 0 });
+    socket.send(Message::Text(record.to_string())).await.unwrap();
+    let Some(Ok(Message::Text(text))) = socket.next().await else { panic!("no error returned") };
+    assert_eq!(serde_json::from_str::<Value>(&text).unwrap()["code"], "unauthorized");
+}
+
+#[tokio::test]
+async fn enrichment_gives_up_on_a_geocoder_that_never_responds() {
+    // The connections are accepted by the system but never answered
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let geocoder = Geocoder::new(&url, Duration::from_millis(200)).unwrap();
+    let router = router_with(App { geocoder: Some(Arc::new(geocoder)), ..test_state().await });
+    let records: Vec<Value> = (0..40)
+        .map(|i| json!({ "source": "phone", "latitude": i, "longitude": i }))
+        .collect();
+    post(&router, "/batch", Value::Array(records)).await;
+
+    // Failed lookups are given up after the timeout, and not retried right away
+    let (status, records) = get(&router, "/?enrich=true&limit=10").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(records.as_array().unwrap().iter().all(|r| r["place"].is_null()));
+    let started = std::time::Instant::now();
+    get(&router, "/?enrich=true&limit=10").await;
+    assert!(started.elapsed() < Duration::from_millis(150), "took {:?}", started.elapsed());
+
+    // Looking every other place up in turn would take 6 seconds
+    let started = std::time::Instant::now();
+    let (status, records) = get(&router, "/?enrich=true").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(started.elapsed() < Duration::from_secs(2), "took {:?}", started.elapsed());
+    let records = records.as_array().unwrap();
+    assert_eq!(records.len(), 40);
+    assert!(records.iter().all(|r| r["place"].is_null()));
+    drop(listener);
+}
+
+#[tokio::test]
+async fn docs_serve_swagger_ui_and_the_specification() {
+    let router = test_router().await;
+
+    let response = router.clone().oneshot(Request::get("/docs").body(Body::empty()).unwrap()).await.unwrap();
+    assert!(response.status().is_redirection());
+    assert_eq!(response.headers()[header::LOCATION], "/docs/index.html");
+    let response = router.clone().oneshot(Request::get("/docs/index.html").body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let (status, spec) = get(&router, "/openapi.json").await;
+    assert_eq!(status, StatusCode::OK);
+    let created = &spec["paths"]["/"]["post"]["responses"]["201"]["content"]["application/json"]["schema"];
+    assert_eq!(created["$ref"], "#/components/schemas/CreatedBody");
+    assert_eq!(spec["components"]["schemas"]["CreatedBody"]["oneOf"].as_array().unwrap().len(), 2);
+    assert!(spec["components"]["schemas"]["ErrorBody"]["properties"]["field"].is_object());
+}
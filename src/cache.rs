@@ -0,0 +1,43 @@
+//! In-memory cache of query results.
+//!
+//! Results are kept for a fixed time to live and never invalidated on writes, so they may lag behind
+//! the database by up to that long.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Cache of values by key, each expiring a fixed time after it was inserted.
+#[derive(Debug)]
+pub struct TtlCache<T> {
+    entries: Mutex<HashMap<String, (Instant, T)>>,
+    ttl: Duration,
+}
+
+impl<T: Clone> TtlCache<T> {
+    /// Create an empty cache whose values live for `ttl`. A zero `ttl` disables caching.
+    pub fn new(ttl: Duration) -> Self {
+        TtlCache {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Get the value of `key`, unless it is missing or has expired.
+    pub fn get(&self, key: &str) -> Option<T> {
+        let entries = self.entries.lock().expect("cache lock poisoned");
+        entries.get(key)
+            .filter(|(inserted_at, _)| inserted_at.elapsed() < self.ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Set the value of `key`, dropping the expired values at the same time so the cache stays small.
+    pub fn insert(&self, key: String, value: T) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        entries.retain(|_, (inserted_at, _)| inserted_at.elapsed() < self.ttl);
+        entries.insert(key, (Instant::now(), value));
+    }
+}
@@ -0,0 +1,90 @@
+//! Naming of the fields of JSON responses.
+//!
+//! Responses are serialised with snake_case keys such as `created_at`. Frontends expecting camelCase
+//! can opt into having every key of JSON responses renamed, such as `createdAt`, with
+//! `JSON_FIELD_CASE=camel`.
+
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+/// Naming of the fields of JSON responses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FieldCase {
+    /// As serialised, such as `created_at`.
+    #[default]
+    Snake,
+    /// Such as `createdAt`.
+    Camel,
+}
+
+/// Error returned when parsing an unknown [`FieldCase`].
+#[derive(Debug, Error)]
+#[error("must be \"snake\" or \"camel\"")]
+pub struct InvalidFieldCase;
+
+impl FromStr for FieldCase {
+    type Err = InvalidFieldCase;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snake" => Ok(FieldCase::Snake),
+            "camel" => Ok(FieldCase::Camel),
+            _ => Err(InvalidFieldCase),
+        }
+    }
+}
+
+/// Convert a snake_case key to camelCase.
+pub fn camel_case(key: &str) -> String {
+    let mut camel = String::with_capacity(key.len());
+    let mut upper = false;
+    for c in key.chars() {
+        if c == '_' && !camel.is_empty() {
+            upper = true;
+        } else if upper {
+            camel.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            camel.push(c);
+        }
+    }
+    camel
+}
+
+/// Rename every key of `value` to camelCase, recursively.
+pub fn camel_case_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let map: Map<String, Value> = map
+                .into_iter()
+                .map(|(key, value)| (camel_case(&key), camel_case_keys(value)))
+                .collect();
+            Value::Object(map)
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(camel_case_keys).collect()),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn camel_case_renames_words() {
+        assert_eq!(camel_case("created_at"), "createdAt");
+        assert_eq!(camel_case("age_secs"), "ageSecs");
+        assert_eq!(camel_case("latitude"), "latitude");
+        assert_eq!(camel_case("_private"), "_private");
+    }
+
+    #[test]
+    fn camel_case_keys_is_recursive() {
+        let value = json!({ "data": [{ "created_at": "x" }], "page": { "has_more": true } });
+        let expected = json!({ "data": [{ "createdAt": "x" }], "page": { "hasMore": true } });
+        assert_eq!(camel_case_keys(value), expected);
+    }
+}
@@ -0,0 +1,165 @@
+//! Errors returned to clients by the handlers.
+
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use thiserror::Error;
+
+/// Reason a source string was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum SourceError {
+    #[error("must not be empty")]
+    Empty,
+
+    #[error("must be at most {max} characters long")]
+    TooLong { max: usize },
+
+    #[error("contains disallowed characters")]
+    DisallowedCharacters,
+}
+
+/// Error returned to the client.
+///
+/// It is serialised as `{"error": "...", "code": "..."}`, where `error` is a human-readable message
+/// and `code` is a stable, machine-readable identifier. Errors about a field of a JSON body also
+/// name it in `field`.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("Invalid source: {0}")]
+    InvalidSource(#[from] SourceError),
+
+    #[error("{0}")]
+    InvalidCoordinates(String),
+
+    #[error("{0}")]
+    InvalidTimestamp(String),
+
+    #[error("{0}")]
+    InvalidParameter(String),
+
+    /// A record of a batch failed validation.
+    #[error("Invalid record at index {index}: {error}")]
+    InvalidRecord { index: usize, error: Box<ApiError> },
+
+    /// A set of filters of `POST /query` failed validation.
+    #[error("Invalid query at index {index}: {error}")]
+    InvalidQuery { index: usize, error: Box<ApiError> },
+
+    /// The JSON body of the request is malformed or has values of the wrong type. `field` is the
+    /// path of the offending field, such as `latitude` or `[1].source`, when there is one.
+    #[error("Invalid JSON body: {message}")]
+    InvalidJson { message: String, field: Option<String> },
+
+    /// The body of the request is not declared as JSON.
+    #[error("Expected a request body with Content-Type: application/json")]
+    UnsupportedMediaType,
+
+    /// Renaming a source would merge it into another one, which was not asked for.
+    #[error("Source {0:?} already exists, set merge to true to merge into it")]
+    SourceExists(String),
+
+    /// A batch holds more records than allowed in a single transaction.
+    #[error("Too many records in batch, at most {max} are allowed")]
+    BatchTooLarge { max: usize },
+
+    #[error("Invalid API key")]
+    Unauthorized,
+
+    /// The instance was started with `READ_ONLY`, so writes are forbidden.
+    #[error("This instance is read-only")]
+    ReadOnly,
+
+    #[error("Record not found")]
+    NotFound,
+
+    /// No route matches the path of the request.
+    #[error("not found")]
+    RouteNotFound,
+
+    /// The route of the request doesn't support its method.
+    #[error("method not allowed")]
+    MethodNotAllowed,
+
+    /// The handler took longer than `REQUEST_TIMEOUT_SECS` to respond.
+    #[error("Request timed out")]
+    RequestTimeout,
+
+    /// The source wrote too many records recently, and may retry after the given number of seconds.
+    #[error("Too many records from this source, retry in {retry_after} seconds")]
+    RateLimited { retry_after: u64 },
+
+    /// A database query failed. The message says what could not be done, the cause itself is only
+    /// logged.
+    #[error("{0}")]
+    Database(&'static str),
+
+    #[error("{0}")]
+    Internal(&'static str),
+}
+
+impl ApiError {
+    /// HTTP status code of the error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidSource(_)
+            | ApiError::InvalidCoordinates(_)
+            | ApiError::InvalidTimestamp(_)
+            | ApiError::InvalidParameter(_)
+            | ApiError::InvalidRecord { .. }
+            | ApiError::InvalidQuery { .. } => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::ReadOnly => StatusCode::FORBIDDEN,
+            ApiError::NotFound | ApiError::RouteNotFound => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::InvalidJson { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::BatchTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::SourceExists(_) => StatusCode::CONFLICT,
+            ApiError::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Database(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine-readable code of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidSource(_) => "invalid_source",
+            ApiError::InvalidCoordinates(_) => "invalid_coordinates",
+            ApiError::InvalidTimestamp(_) => "invalid_timestamp",
+            ApiError::InvalidParameter(_) => "invalid_parameter",
+            ApiError::InvalidRecord { error, .. } | ApiError::InvalidQuery { error, .. } => error.code(),
+            ApiError::InvalidJson { .. } => "invalid_json",
+            ApiError::UnsupportedMediaType => "unsupported_media_type",
+            ApiError::BatchTooLarge { .. } => "batch_too_large",
+            ApiError::SourceExists(_) => "source_exists",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::ReadOnly => "read_only",
+            ApiError::NotFound => "not_found",
+            ApiError::RouteNotFound => "route_not_found",
+            ApiError::MethodNotAllowed => "method_not_allowed",
+            ApiError::RequestTimeout => "request_timeout",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Database(_) => "db_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut body = json!({
+            "error": self.to_string(),
+            "code": self.code(),
+        });
+        if let ApiError::InvalidJson { field: Some(ref field), .. } = self {
+            body["field"] = json!(field);
+        }
+        let mut response = (self.status(), Json(body)).into_response();
+        if let ApiError::RateLimited { retry_after } = self {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
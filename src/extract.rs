@@ -0,0 +1,93 @@
+//! Extractors rejecting invalid requests with the same JSON errors as the handlers.
+
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::header;
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+
+use crate::error::ApiError;
+use crate::timestamp;
+
+/// Query string deserialised into `T`, like `axum::extract::Query`.
+///
+/// Invalid query strings are rejected as `ApiError`s rather than in plain text, with the
+/// `invalid_timestamp` code when a timestamp could not be parsed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        match axum::extract::Query::try_from_uri(&parts.uri) {
+            Ok(axum::extract::Query(value)) => Ok(Query(value)),
+            Err(rejection) => {
+                let message = rejection.body_text();
+                if message.contains(timestamp::ACCEPTED_FORMATS) {
+                    Err(ApiError::InvalidTimestamp(message))
+                } else {
+                    Err(ApiError::InvalidParameter(message))
+                }
+            }
+        }
+    }
+}
+
+/// JSON body deserialised into `T`, like `axum::Json`.
+///
+/// Bodies that are not JSON, or whose values have the wrong type, are rejected as
+/// `ApiError::InvalidJson` naming the offending field when there is one, such as `latitude` or
+/// `[1].source` in an array, rather than in plain text. Bodies that cannot be read, such as those
+/// over the size limit, are rejected as by axum.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !is_json(&request) {
+            return Err(ApiError::UnsupportedMediaType.into_response());
+        }
+        let bytes = Bytes::from_request(request, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let mut deserializer = serde_json::Deserializer::from_slice(&bytes);
+        let value = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+            // The root of the body is not a field, and keys that could not be read are unknown
+            let path = e.path().to_string();
+            let path = path.trim_end_matches('?').trim_end_matches('.');
+            let field = Some(path.to_string()).filter(|path| !path.is_empty());
+            ApiError::InvalidJson { message: e.inner().to_string(), field }.into_response()
+        })?;
+        // Only whitespace may follow the value
+        deserializer.end().map_err(|e| {
+            ApiError::InvalidJson { message: e.to_string(), field: None }.into_response()
+        })?;
+        Ok(JsonBody(value))
+    }
+}
+
+/// Helper function to tell whether the body of a request is declared as JSON, such as
+/// `application/json` or `application/geo+json`.
+fn is_json(request: &Request) -> bool {
+    let Some(content_type) = request.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    media_type == "application/json" || (media_type.starts_with("application/") && media_type.ends_with("+json"))
+}
@@ -0,0 +1,291 @@
+//! Alternative output formats for location records.
+
+use axum::body::Body;
+use serde_json::{json, Value};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+
+use crate::quality::QualityThresholds;
+use crate::{timestamp, DbLocData, RatedLocData};
+
+/// Media type of GeoJSON documents.
+pub const GEOJSON_CONTENT_TYPE: &str = "application/geo+json";
+
+/// Convert location records into a GeoJSON `FeatureCollection` of `Point` features.
+///
+/// GeoJSON orders coordinates as `[longitude, latitude]`, followed by the altitude when known; the
+/// remaining fields are carried in the feature's `properties`.
+pub fn geojson(data: &[DbLocData]) -> Value {
+    let features: Vec<Value> = data
+        .iter()
+        .map(|loc| {
+            let coordinates = match loc.altitude {
+                Some(altitude) => vec![loc.longitude, loc.latitude, altitude],
+                None => vec![loc.longitude, loc.latitude],
+            };
+            json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": coordinates,
+                },
+                "properties": {
+                    "id": loc.id,
+                    "source": loc.source,
+                    "created_at": timestamp::format(&loc.created_at),
+                },
+            })
+        })
+        .collect();
+
+    json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+}
+
+/// Media type of CSV documents.
+pub const CSV_CONTENT_TYPE: &str = "text/csv";
+
+/// Columns of CSV documents, in the field order of [`DbLocData`].
+const CSV_COLUMNS: [&str; 12] = [
+    "id",
+    "source",
+    "latitude",
+    "longitude",
+    "altitude",
+    "accuracy",
+    "speed",
+    "battery",
+    "provider",
+    "tags",
+    "created_at",
+    "deleted_at",
+];
+
+/// Convert location records into a CSV document with a header line.
+///
+/// Columns follow the field order of [`DbLocData`], and the `csv` crate takes care of quoting. The
+/// header is written up front rather than along with the first record, so that it is there even
+/// when no record matches.
+pub fn csv(data: &[DbLocData]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+    writer.write_record(CSV_COLUMNS)?;
+    for loc in data {
+        writer.serialize(loc)?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+/// Media type of GPX documents.
+pub const GPX_CONTENT_TYPE: &str = "application/gpx+xml";
+
+/// Convert location records into a GPX 1.1 document with a single track.
+///
+/// Records are written in the given order, so they should already be sorted by `created_at`.
+/// Timestamps are stored in UTC, hence the `Z` suffix on each `<time>`.
+pub fn gpx(data: &[DbLocData], name: &str) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<gpx version=\"1.1\" creator=\"ntb_location\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n");
+    out.push_str("  <trk>\n");
+    out.push_str(&format!("    <name>{}</name>\n", xml_escape(name)));
+    out.push_str("    <trkseg>\n");
+    for loc in data {
+        let ele = loc.altitude
+            .map(|altitude| format!("<ele>{}</ele>", altitude))
+            .unwrap_or_default();
+        out.push_str(&format!(
+            "      <trkpt lat=\"{}\" lon=\"{}\">{}<time>{}</time></trkpt>\n",
+            loc.latitude,
+            loc.longitude,
+            ele,
+            timestamp::format(&loc.created_at),
+        ));
+    }
+    out.push_str("    </trkseg>\n");
+    out.push_str("  </trk>\n");
+    out.push_str("</gpx>\n");
+    out
+}
+
+/// Media type of KML documents.
+pub const KML_CONTENT_TYPE: &str = "application/vnd.google-earth.kml+xml";
+
+/// Convert location records into a KML 2.2 document with a `Placemark` per point.
+///
+/// KML orders coordinates as `longitude,latitude[,altitude]`. Each placemark is named after its
+/// source and carries its `TimeStamp`, so that Google Earth can play the points back in time.
+pub fn kml(data: &[DbLocData], name: &str) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<kml xmlns=\"http://www.opengis.net/kml/2.2\">\n");
+    out.push_str("  <Document>\n");
+    out.push_str(&format!("    <name>{}</name>\n", xml_escape(name)));
+    for loc in data {
+        let coordinates = match loc.altitude {
+            Some(altitude) => format!("{},{},{}", loc.longitude, loc.latitude, altitude),
+            None => format!("{},{}", loc.longitude, loc.latitude),
+        };
+        out.push_str(&format!(
+            "    <Placemark><name>{}</name><TimeStamp><when>{}</when></TimeStamp><Point><coordinates>{}</coordinates></Point></Placemark>\n",
+            xml_escape(&loc.source),
+            timestamp::format(&loc.created_at),
+            coordinates,
+        ));
+    }
+    out.push_str("  </Document>\n");
+    out.push_str("</kml>\n");
+    out
+}
+
+/// Escape the characters that are special in XML text and attribute values.
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Encode coordinates with Google's encoded polyline algorithm, at 5 decimal places.
+///
+/// Each coordinate is written as the difference from the previous one, so close points take only a
+/// few characters. The result only contains printable ASCII from `?` to `~`.
+pub fn encode_polyline(points: impl IntoIterator<Item = (f64, f64)>) -> String {
+    let mut out = String::new();
+    let (mut prev_lat, mut prev_lon) = (0i64, 0i64);
+    for (lat, lon) in points {
+        let (lat, lon) = ((lat * 1e5).round() as i64, (lon * 1e5).round() as i64);
+        encode_polyline_value(lat - prev_lat, &mut out);
+        encode_polyline_value(lon - prev_lon, &mut out);
+        (prev_lat, prev_lon) = (lat, lon);
+    }
+    out
+}
+
+/// Append a signed value to an encoded polyline, in chunks of 5 bits from the lowest ones.
+fn encode_polyline_value(value: i64, out: &mut String) {
+    let mut value = if value < 0 { !(value << 1) } else { value << 1 };
+    while value >= 0x20 {
+        out.push(char::from((0x20 | (value & 0x1f)) as u8 + 63));
+        value >>= 5;
+    }
+    out.push(char::from(value as u8 + 63));
+}
+
+/// Convert location records into one encoded polyline per source, in order of first appearance.
+///
+/// The points of each track are ordered by `created_at`, whatever the order of the records, so the
+/// line follows the path actually taken.
+pub fn polylines(data: &[DbLocData]) -> Value {
+    let mut tracks: Vec<(&str, Vec<&DbLocData>)> = Vec::new();
+    for loc in data {
+        match tracks.iter_mut().find(|(source, _)| *source == loc.source) {
+            Some((_, points)) => points.push(loc),
+            None => tracks.push((&loc.source, vec![loc])),
+        }
+    }
+    let tracks: Vec<Value> = tracks
+        .into_iter()
+        .map(|(source, mut points)| {
+            points.sort_by_key(|loc| loc.created_at);
+            json!({
+                "source": source,
+                "points": points.len(),
+                "polyline": encode_polyline(points.iter().map(|loc| (loc.latitude, loc.longitude))),
+            })
+        })
+        .collect();
+    Value::Array(tracks)
+}
+
+/// Media type of newline-delimited JSON documents.
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Stream the records selected by `builder` as newline-delimited JSON, one object per line.
+///
+/// Records carry their quality, like JSON ones. Rows are fetched and written one at a time by a
+/// background task, so memory stays flat regardless of the size of the result set. A database
+/// error mid-stream aborts the response.
+pub fn ndjson_body(
+    pool: SqlitePool,
+    mut builder: QueryBuilder<'static, Sqlite>,
+    quality: QualityThresholds,
+) -> Body {
+    let (tx, rx) = mpsc::channel::<Result<Vec<u8>, sqlx::Error>>(64);
+
+    tokio::spawn(async move {
+        let mut rows = builder.build_query_as::<DbLocData>().fetch(&pool);
+        while let Some(row) = rows.next().await {
+            let line = row.map(|location| {
+                let record = RatedLocData { quality: quality.quality(location.accuracy), location };
+                let mut line = serde_json::to_vec(&record).expect("RatedLocData is always serialisable");
+                line.push(b'\n');
+                line
+            });
+            let failed = line.is_err();
+            if let Err(ref e) = line {
+                tracing::error!("Cannot stream records: {:?}", e);
+            }
+            // Stop early when the client has gone away or the query failed
+            if tx.send(line).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    Body::from_stream(ReceiverStream::new(rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::NaiveDateTime;
+
+    fn record(id: i64) -> DbLocData {
+        DbLocData {
+            id,
+            source: "phone".to_string(),
+            latitude: 1.5,
+            longitude: 2.5,
+            altitude: None,
+            accuracy: None,
+            speed: None,
+            battery: None,
+            provider: None,
+            tags: None,
+            created_at: NaiveDateTime::default(),
+            deleted_at: None,
+        }
+    }
+
+    #[test]
+    fn csv_writes_the_header_even_without_records() {
+        let header = format!("{}\n", CSV_COLUMNS.join(","));
+        assert_eq!(String::from_utf8(csv(&[]).unwrap()).unwrap(), header);
+
+        // The header matches the columns serialised from the records
+        let body = String::from_utf8(csv(&[record(1)]).unwrap()).unwrap();
+        let mut reader = csv::Reader::from_reader(body.as_bytes());
+        assert_eq!(reader.headers().unwrap(), CSV_COLUMNS.as_slice());
+        let rows: Vec<_> = reader.deserialize::<DbLocData>().collect::<Result<_, _>>().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].id, rows[0].latitude), (1, 1.5));
+    }
+
+    #[test]
+    fn encode_polyline_matches_the_reference_example() {
+        let points = [(38.5, -120.2), (40.7, -120.95), (43.252, -126.453)];
+        assert_eq!(encode_polyline(points), "_p~iF~ps|U_ulLnnqC_mqNvxq`@");
+    }
+
+    #[test]
+    fn encode_polyline_handles_no_points_and_the_origin() {
+        assert_eq!(encode_polyline([]), "");
+        assert_eq!(encode_polyline([(0.0, 0.0)]), "??");
+    }
+}
@@ -0,0 +1,124 @@
+//! Geographic helper functions.
+//!
+//! Points are given as `(latitude, longitude)` tuples in degrees throughout.
+
+/// Mean radius of the Earth in kilometres.
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Great-circle distance between two points in kilometres, using the Haversine formula.
+pub fn haversine_km(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (a.0.to_radians(), a.1.to_radians());
+    let (lat2, lon2) = (b.0.to_radians(), b.1.to_radians());
+
+    let h = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+
+    // Rounding errors can push `h` slightly above 1 for antipodal points
+    2.0 * EARTH_RADIUS_KM * h.min(1.0).sqrt().asin()
+}
+
+/// Bounding box containing every point within `radius_km` of `center`.
+///
+/// Returned as `(min_lat, max_lat, min_lon, max_lon)`. The box is conservative: near the poles or
+/// when it would cross the antimeridian, it spans all longitudes rather than wrapping around.
+pub fn bounding_box(center: (f64, f64), radius_km: f64) -> (f64, f64, f64, f64) {
+    let delta_lat = (radius_km / EARTH_RADIUS_KM).to_degrees();
+    let min_lat = (center.0 - delta_lat).max(-90.0);
+    let max_lat = (center.0 + delta_lat).min(90.0);
+
+    // The widest point of the box is at the latitude closest to a pole
+    let widest_lat = min_lat.abs().max(max_lat.abs());
+    if widest_lat >= 90.0 {
+        return (min_lat, max_lat, -180.0, 180.0);
+    }
+    let delta_lon = delta_lat / widest_lat.to_radians().cos();
+    let min_lon = center.1 - delta_lon;
+    let max_lon = center.1 + delta_lon;
+    if min_lon < -180.0 || max_lon > 180.0 {
+        return (min_lat, max_lat, -180.0, 180.0);
+    }
+
+    (min_lat, max_lat, min_lon, max_lon)
+}
+
+/// Position of `point` in metres east and north of `origin`, using an equirectangular projection.
+///
+/// It is accurate enough over the extent of a track, as long as it doesn't cross the antimeridian.
+pub fn project_m(origin: (f64, f64), point: (f64, f64)) -> (f64, f64) {
+    let scale = EARTH_RADIUS_KM * 1000.0;
+    let x = (point.1 - origin.1).to_radians() * origin.0.to_radians().cos() * scale;
+    let y = (point.0 - origin.0).to_radians() * scale;
+    (x, y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    const PARIS: (f64, f64) = (48.8566, 2.3522);
+    const LYON: (f64, f64) = (45.7640, 4.8357);
+
+    #[test]
+    fn haversine_km_measures_known_distances() {
+        assert!((haversine_km(PARIS, LYON) - 392.0).abs() < 2.0);
+        assert_eq!(haversine_km(PARIS, LYON), haversine_km(LYON, PARIS));
+        assert_eq!(haversine_km(PARIS, PARIS), 0.0);
+    }
+
+    #[test]
+    fn haversine_km_handles_antipodal_points() {
+        // The formula is ill-conditioned there, so allow for a metre of rounding
+        let half_circumference = PI * EARTH_RADIUS_KM;
+        assert!((haversine_km((0.0, 0.0), (0.0, 180.0)) - half_circumference).abs() < 1e-3);
+        assert!((haversine_km((10.0, 20.0), (-10.0, -160.0)) - half_circumference).abs() < 1e-3);
+    }
+
+    #[test]
+    fn bounding_box_contains_the_circle() {
+        let (min_lat, max_lat, min_lon, max_lon) = bounding_box(PARIS, 10.0);
+        assert!(min_lat < PARIS.0 && PARIS.0 < max_lat);
+        assert!(min_lon < PARIS.1 && PARIS.1 < max_lon);
+        // The edges are 10 km away along the meridian and the parallel
+        assert!((haversine_km(PARIS, (max_lat, PARIS.1)) - 10.0).abs() < 1e-6);
+        assert!(haversine_km(PARIS, (PARIS.0, max_lon)) >= 10.0);
+    }
+
+    #[test]
+    fn bounding_box_spans_all_longitudes_near_the_poles() {
+        let (min_lat, max_lat, min_lon, max_lon) = bounding_box((89.95, 10.0), 10.0);
+        assert_eq!(max_lat, 90.0);
+        assert!(min_lat < 89.95);
+        assert_eq!((min_lon, max_lon), (-180.0, 180.0));
+
+        let (min_lat, _, min_lon, max_lon) = bounding_box((-89.99, 0.0), 5.0);
+        assert_eq!(min_lat, -90.0);
+        assert_eq!((min_lon, max_lon), (-180.0, 180.0));
+    }
+
+    #[test]
+    fn bounding_box_spans_all_longitudes_across_the_antimeridian() {
+        let (_, _, min_lon, max_lon) = bounding_box((0.0, 179.95), 10.0);
+        assert_eq!((min_lon, max_lon), (-180.0, 180.0));
+
+        let (_, _, min_lon, max_lon) = bounding_box((0.0, -179.95), 10.0);
+        assert_eq!((min_lon, max_lon), (-180.0, 180.0));
+    }
+
+    #[test]
+    fn project_m_gives_metres_east_and_north() {
+        assert_eq!(project_m(PARIS, PARIS), (0.0, 0.0));
+
+        // A thousandth of a degree is about 111 m along the meridian, less along the parallel
+        let (x, y) = project_m((0.0, 0.0), (0.001, 0.001));
+        assert!((x - 111.19).abs() < 0.01 && (y - 111.19).abs() < 0.01);
+        let (x, y) = project_m((60.0, 0.0), (60.0, 0.001));
+        assert!((x - 55.6).abs() < 0.1 && y == 0.0);
+
+        // The projection is close to the great-circle distance over short spans
+        let point = (PARIS.0 + 0.01, PARIS.1 - 0.02);
+        let (x, y) = project_m(PARIS, point);
+        assert!((x.hypot(y) - haversine_km(PARIS, point) * 1000.0).abs() < 1.0);
+        assert!(x < 0.0 && y > 0.0);
+    }
+}
@@ -0,0 +1,166 @@
+//! Reverse geocoding of locations into place names.
+//!
+//! Place names come from a Nominatim-compatible service. Lookups are cached by coordinates rounded
+//! to about a hundred metres, and any failure simply leaves the place unknown.
+//!
+//! The service may be slow or down, so enriching a page of records must not wait on it for every
+//! record in turn. Lookups of a page run concurrently under a single deadline, at most
+//! `MAX_LOOKUPS` of them, and failures are remembered for a while so that they are not retried by
+//! every request.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+/// Coordinates are rounded to this many decimal places before lookup, about 110 m in latitude.
+const PRECISION: f64 = 1000.0;
+
+/// Maximum number of cached places, after which the cache is cleared.
+const MAX_CACHED: usize = 10000;
+
+/// Maximum number of lookups made for a single page of records. The places of the others are left
+/// unknown, and looked up by later requests.
+const MAX_LOOKUPS: usize = 16;
+
+/// Time for which a failed lookup is remembered, during which the place is left unknown.
+const FAILURE_TTL: Duration = Duration::from_secs(60);
+
+/// Place cached for rounded coordinates.
+#[derive(Debug, Clone)]
+struct CachedPlace {
+    place: Option<String>,
+    /// When a failed lookup may be retried, `None` for successful ones which never expire.
+    retry_at: Option<Instant>,
+}
+
+impl CachedPlace {
+    /// Place found by a lookup, which may be unknown to the service.
+    fn found(place: Option<String>) -> Self {
+        CachedPlace { place, retry_at: None }
+    }
+
+    /// Lookup that failed or did not finish in time.
+    fn failed() -> Self {
+        CachedPlace { place: None, retry_at: Some(Instant::now() + FAILURE_TTL) }
+    }
+}
+
+/// Part of the response of the `/reverse` endpoint of Nominatim that is used.
+#[derive(Debug, Deserialize)]
+struct ReverseResponse {
+    display_name: Option<String>,
+}
+
+/// Client of a reverse geocoding service.
+#[derive(Debug)]
+pub struct Geocoder {
+    client: reqwest::Client,
+    base_url: String,
+    timeout: Duration,
+    cache: Mutex<HashMap<(i64, i64), CachedPlace>>,
+}
+
+impl Geocoder {
+    /// Create a client of the service at `base_url`, giving up on lookups after `timeout`.
+    pub fn new(base_url: &str, timeout: Duration) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            // Nominatim's usage policy requires identifying the application
+            .user_agent(concat!("ntb_location/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .context("Cannot create geocoder client")?;
+        Ok(Geocoder {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            timeout,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Names of the places at the given points, `None` when unknown or not looked up in time.
+    ///
+    /// Points missing from the cache are looked up concurrently, at most `MAX_LOOKUPS` of them, and
+    /// the lookups still running after the timeout of the client are given up.
+    pub async fn places(self: &Arc<Self>, points: &[(f64, f64)]) -> Vec<Option<String>> {
+        let keys: Vec<(i64, i64)> = points.iter().map(|&(latitude, longitude)| key(latitude, longitude)).collect();
+
+        // Look up the places that are not cached, or whose lookup failed long enough ago
+        let now = Instant::now();
+        let missing: HashSet<(i64, i64)> = {
+            let cache = self.cache.lock().expect("geocoder cache lock poisoned");
+            keys.iter()
+                .filter(|key| cache.get(key).is_none_or(|cached| cached.retry_at.is_some_and(|at| at <= now)))
+                .copied()
+                .collect()
+        };
+        let mut pending: HashSet<(i64, i64)> = missing.into_iter().take(MAX_LOOKUPS).collect();
+        let mut lookups = JoinSet::new();
+        for &key in &pending {
+            let geocoder = Arc::clone(self);
+            lookups.spawn(async move { (key, geocoder.lookup_key(key).await) });
+        }
+        let deadline = now + self.timeout;
+        while let Ok(Some(joined)) = tokio::time::timeout_at(deadline, lookups.join_next()).await {
+            if let Ok((key, result)) = joined {
+                pending.remove(&key);
+                self.store(key, result.map_or_else(|_| CachedPlace::failed(), CachedPlace::found));
+            }
+        }
+        // Dropping the set aborts the lookups that did not finish in time, which count as failed
+        drop(lookups);
+        for key in pending {
+            self.store(key, CachedPlace::failed());
+        }
+
+        let cache = self.cache.lock().expect("geocoder cache lock poisoned");
+        keys.iter()
+            .map(|key| cache.get(key).and_then(|cached| cached.place.clone()))
+            .collect()
+    }
+
+    /// Look up the place at rounded coordinates.
+    async fn lookup_key(&self, key: (i64, i64)) -> Result<Option<String>, reqwest::Error> {
+        let (latitude, longitude) = (key.0 as f64 / PRECISION, key.1 as f64 / PRECISION);
+        let result = self.lookup(latitude, longitude).await;
+        if let Err(ref e) = result {
+            tracing::warn!("Cannot reverse geocode ({}, {}): {:?}", latitude, longitude, e);
+        }
+        result
+    }
+
+    /// Cache the result of a lookup.
+    fn store(&self, key: (i64, i64), cached: CachedPlace) {
+        let mut cache = self.cache.lock().expect("geocoder cache lock poisoned");
+        if cache.len() >= MAX_CACHED {
+            cache.clear();
+        }
+        cache.insert(key, cached);
+    }
+
+    /// Query the service for the place at the given coordinates.
+    async fn lookup(&self, latitude: f64, longitude: f64) -> Result<Option<String>, reqwest::Error> {
+        let response: ReverseResponse = self.client
+            .get(format!("{}/reverse", self.base_url))
+            .query(&[
+                ("format", "jsonv2".to_string()),
+                ("lat", latitude.to_string()),
+                ("lon", longitude.to_string()),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.display_name)
+    }
+}
+
+/// Helper function to round coordinates into a key of the cache.
+fn key(latitude: f64, longitude: f64) -> (i64, i64) {
+    ((latitude * PRECISION).round() as i64, (longitude * PRECISION).round() as i64)
+}
@@ -0,0 +1,253 @@
+//! Import of location records from GPX and CSV files, the mirror of the export formats.
+
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde::Deserialize;
+
+use crate::formats::{CSV_CONTENT_TYPE, GPX_CONTENT_TYPE};
+use crate::{empty_string_as_none, empty_string_as_utc, tag_list, timestamp, PostLocData};
+
+/// Format of an imported file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    Gpx,
+    Csv,
+}
+
+impl ImportFormat {
+    /// Detect the format from the media type of an uploaded file, ignoring its parameters.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        match media_type {
+            GPX_CONTENT_TYPE => Some(ImportFormat::Gpx),
+            CSV_CONTENT_TYPE => Some(ImportFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gpx" => Ok(ImportFormat::Gpx),
+            "csv" => Ok(ImportFormat::Csv),
+            _ => Err(format!("Invalid format {}: must be gpx or csv", s)),
+        }
+    }
+}
+
+/// Parse the points of a file into location records of `source`.
+///
+/// Each point is parsed on its own, so that a point that cannot be read is reported at its index
+/// without failing the others. Only a file that cannot be read at all is an error.
+pub fn parse(format: ImportFormat, data: &[u8], source: &str) -> Result<Vec<Result<PostLocData, String>>, String> {
+    match format {
+        ImportFormat::Gpx => parse_gpx(data, source),
+        ImportFormat::Csv => Ok(parse_csv(data, source)),
+    }
+}
+
+/// Row of an imported CSV document, with the columns of the export. Other columns are ignored.
+#[derive(Debug, Deserialize)]
+struct CsvRow {
+    latitude: f64,
+    longitude: f64,
+    altitude: Option<f64>,
+    accuracy: Option<f64>,
+    speed: Option<f64>,
+    battery: Option<f64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    provider: Option<String>,
+    #[serde(default, deserialize_with = "tag_list")]
+    tags: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    created_at: Option<NaiveDateTime>,
+}
+
+/// Parse the rows of a CSV document with a header line.
+fn parse_csv(data: &[u8], source: &str) -> Vec<Result<PostLocData, String>> {
+    let mut reader = csv::Reader::from_reader(data);
+    reader
+        .deserialize::<CsvRow>()
+        .map(|row| {
+            let row = row.map_err(|e| e.to_string())?;
+            Ok(PostLocData {
+                source: source.to_string(),
+                latitude: row.latitude,
+                longitude: row.longitude,
+                altitude: row.altitude,
+                accuracy: row.accuracy,
+                speed: row.speed,
+                battery: row.battery,
+                provider: row.provider,
+                tags: row.tags,
+                created_at: row.created_at,
+            })
+        })
+        .collect()
+}
+
+/// Point of a GPX document being read.
+#[derive(Debug, Default)]
+struct GpxPoint {
+    latitude: Option<String>,
+    longitude: Option<String>,
+    elevation: Option<String>,
+    time: Option<String>,
+}
+
+/// Child element of a point whose text is being read.
+#[derive(Debug, Clone, Copy)]
+enum GpxField {
+    Elevation,
+    Time,
+}
+
+/// Parse the track, route and way points of a GPX document, in document order.
+fn parse_gpx(data: &[u8], source: &str) -> Result<Vec<Result<PostLocData, String>>, String> {
+    let text = std::str::from_utf8(data).map_err(|_| "Invalid GPX file: not UTF-8".to_string())?;
+    let mut reader = Reader::from_str(text);
+    reader.config_mut().trim_text(true);
+
+    let mut points = Vec::new();
+    let mut point: Option<GpxPoint> = None;
+    let mut field: Option<GpxField> = None;
+    loop {
+        let event = reader.read_event().map_err(|e| format!("Invalid GPX file: {}", e))?;
+        match event {
+            Event::Start(ref e) if is_point(e) => point = Some(start_point(e)),
+            Event::Empty(ref e) if is_point(e) => points.push(gpx_record(start_point(e), source)),
+            Event::Start(ref e) if point.is_some() => {
+                field = match e.local_name().as_ref() {
+                    "ele" => Some(GpxField::Elevation),
+                    "time" => Some(GpxField::Time),
+                    _ => None,
+                };
+            }
+            Event::Text(ref e) => {
+                if let (Some(point), Some(field)) = (point.as_mut(), field) {
+                    let text = e.xml10_content().into_owned();
+                    match field {
+                        GpxField::Elevation => point.elevation = Some(text),
+                        GpxField::Time => point.time = Some(text),
+                    }
+                }
+            }
+            Event::End(ref e) => {
+                if matches!(e.local_name().as_ref(), "trkpt" | "rtept" | "wpt") {
+                    if let Some(point) = point.take() {
+                        points.push(gpx_record(point, source));
+                    }
+                }
+                field = None;
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(points)
+}
+
+/// Helper function to tell whether an element is a point.
+fn is_point(e: &BytesStart) -> bool {
+    matches!(e.local_name().as_ref(), "trkpt" | "rtept" | "wpt")
+}
+
+/// Helper function to read the coordinates of a point from its attributes.
+fn start_point(e: &BytesStart) -> GpxPoint {
+    let attribute = |name: &str| {
+        e.try_get_attribute(name)
+            .ok()
+            .flatten()
+            .map(|a| a.value.into_owned())
+    };
+    GpxPoint {
+        latitude: attribute("lat"),
+        longitude: attribute("lon"),
+        ..GpxPoint::default()
+    }
+}
+
+/// Helper function to convert a point read from a GPX document into a location record.
+fn gpx_record(point: GpxPoint, source: &str) -> Result<PostLocData, String> {
+    let number = |name: &str, value: Option<String>| -> Result<Option<f64>, String> {
+        value
+            .map(|v| v.trim().parse().map_err(|_| format!("Invalid {} {}", name, v)))
+            .transpose()
+    };
+    let latitude = number("lat", point.latitude)?.ok_or("Missing lat")?;
+    let longitude = number("lon", point.longitude)?.ok_or("Missing lon")?;
+    let altitude = number("ele", point.elevation)?;
+    let created_at = point.time
+        .map(|time| timestamp::parse(time.trim()).map_err(|e| format!("Invalid time {}: {}", time, e)))
+        .transpose()?;
+    Ok(PostLocData {
+        source: source.to_string(),
+        latitude,
+        longitude,
+        altitude,
+        accuracy: None,
+        speed: None,
+        battery: None,
+        provider: None,
+        tags: None,
+        created_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_gpx_reads_points() {
+        let gpx = br#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+  <wpt lat="1.5" lon="2.5"/>
+  <trk><trkseg>
+    <trkpt lat="10.0" lon="20.0"><ele>5</ele><time>2024-01-02T03:04:05Z</time></trkpt>
+    <trkpt lat="north" lon="20.0"></trkpt>
+  </trkseg></trk>
+</gpx>"#;
+        let points = parse(ImportFormat::Gpx, gpx, "phone").unwrap();
+        assert_eq!(points.len(), 3);
+
+        let waypoint = points[0].as_ref().unwrap();
+        assert_eq!((waypoint.latitude, waypoint.longitude), (1.5, 2.5));
+
+        let point = points[1].as_ref().unwrap();
+        assert_eq!(point.source, "phone");
+        assert_eq!(point.altitude, Some(5.0));
+        assert_eq!(point.created_at.map(|t| timestamp::format(&t)).as_deref(), Some("2024-01-02T03:04:05Z"));
+
+        assert!(points[2].is_err());
+    }
+
+    #[test]
+    fn parse_csv_reads_rows() {
+        let csv = b"id,source,latitude,longitude,altitude,provider,created_at\n\
+            1,other,10.0,20.0,,gps,2024-01-02T03:04:05Z\n\
+            2,other,x,20.0,,,\n";
+        let points = parse(ImportFormat::Csv, csv, "phone").unwrap();
+        assert_eq!(points.len(), 2);
+
+        let point = points[0].as_ref().unwrap();
+        assert_eq!(point.source, "phone");
+        assert_eq!((point.latitude, point.longitude, point.altitude), (10.0, 20.0, None));
+        assert_eq!(point.provider.as_deref(), Some("gps"));
+
+        assert!(points[1].is_err());
+    }
+
+    #[test]
+    fn format_is_detected_from_content_type() {
+        assert_eq!(ImportFormat::from_content_type("text/csv; charset=utf-8"), Some(ImportFormat::Csv));
+        assert_eq!(ImportFormat::from_content_type("application/gpx+xml"), Some(ImportFormat::Gpx));
+        assert_eq!(ImportFormat::from_content_type("application/octet-stream"), None);
+    }
+}
@@ -1,40 +1,229 @@
+#[cfg(test)]
+mod api_tests;
+mod cache;
+mod case;
+mod error;
+mod extract;
+mod formats;
+mod geocode;
+mod geo;
+mod import;
+mod openapi;
+mod quality;
+mod rate_limit;
+mod render;
+mod simplify;
+mod telemetry;
+mod timestamp;
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::hash::{BuildHasher, DefaultHasher, Hasher, RandomState};
 use std::str::FromStr;
 use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
-use axum::extract::{Query, State};
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use axum::{Json, Router};
-use axum::routing::{get, post};
-use chrono::NaiveDateTime;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{DefaultBodyLimit, Multipart, Path, Request, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, Router, ServiceExt};
+use axum::routing::{delete, get, post, put};
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Utc};
+use log::LevelFilter;
+use metrics_exporter_prometheus::PrometheusHandle;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use serde::{Deserialize, Deserializer, Serialize};
-use sqlx::SqlitePool;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{ConnectOptions, FromRow, QueryBuilder, Sqlite, SqlitePool};
 use tokio::net::TcpListener;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinSet;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::decompression::RequestDecompressionLayer;
+use tower_http::normalize_path::NormalizePath;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::TraceLayer;
-use tracing_subscriber::{prelude::*, fmt, EnvFilter};
+use tracing::Span;
+use utoipa::{IntoParams, ToSchema};
+
+use cache::TtlCache;
+use case::FieldCase;
+use error::{ApiError, SourceError};
+use extract::{JsonBody, Query};
+use geocode::Geocoder;
+use import::ImportFormat;
+use quality::{Quality, QualityThresholds};
+use rate_limit::RateLimiter;
+use telemetry::LogFormat;
 
 /// Regex to sanitise strings.
-/// 
-/// It will be called multiple times so we can make it a static variable.
+///
+/// It will be called multiple times so we can make it a static variable. Besides alphanumerics,
+/// underscores and spaces, it allows `-`, `.` and `:` which are common in device identifiers such
+/// as `phone-01.home`. Quotes, semicolons, slashes and control characters are still rejected.
 static SANITISATION_REGEX: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"^[a-zA-Z0-9_ ]+$").unwrap()
+    Regex::new(r"^[a-zA-Z0-9_ .:-]+$").unwrap()
 });
 
+/// Maximum length of a source string.
+const MAX_SOURCE_LEN: usize = 64;
+
+/// Default maximum size of a `POST /` request body, in bytes.
+const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Default maximum size of a `POST /batch` request body, in bytes.
+const DEFAULT_MAX_BATCH_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+/// Default maximum number of records of a batch, inserted inside a single transaction.
+const DEFAULT_MAX_BATCH_SIZE: usize = 1000;
+
+/// Default time a handler may take to produce its response, in seconds.
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+
+/// Default maximum number of pooled database connections.
+const DEFAULT_DB_MAX_CONNECTIONS: u32 = 10;
+
+/// Default time to wait for a pooled database connection, in seconds.
+const DEFAULT_DB_ACQUIRE_TIMEOUT_SECS: u64 = 30;
+
+/// Default number of attempts at connecting to the database at startup, before giving up.
+const DEFAULT_DB_CONNECT_ATTEMPTS: u32 = 5;
+
+/// Default delay before retrying to connect to the database at startup, doubled on each retry, in
+/// milliseconds.
+const DEFAULT_DB_CONNECT_RETRY_MS: u64 = 500;
+
+/// Default time a connection waits for a lock held by another writer before failing with
+/// `SQLITE_BUSY`, in milliseconds.
+const DEFAULT_DB_BUSY_TIMEOUT_MS: u64 = 5000;
+
+/// Default time after which a database query is logged as slow, in milliseconds.
+const DEFAULT_SLOW_QUERY_MS: u64 = 1000;
+
+/// Number of attempts at an insert failing because the database is locked, before giving up.
+const MAX_BUSY_ATTEMPTS: u32 = 5;
+
+/// Delay before retrying an insert failing because the database is locked, doubled on each retry.
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(25);
+
+/// Default period between refreshes of the record count metric, in seconds.
+const DEFAULT_METRICS_REFRESH_SECS: u64 = 15;
+
+/// Default number of inserts per minute allowed for each source, or 0 for no limit.
+const DEFAULT_RATE_LIMIT_PER_MINUTE: u32 = 0;
+
+/// Period between removals of idle sources from the rate limiter.
+const RATE_LIMIT_CLEANUP_PERIOD: Duration = Duration::from_secs(60);
+
+/// Default distance in metres under which a reading repeating its source's last one is dropped, or
+/// 0 to never drop readings.
+const DEFAULT_DEDUP_DISTANCE_M: f64 = 0.0;
+
+/// Default time window in seconds within which a reading may repeat its source's last one.
+const DEFAULT_DEDUP_WINDOW_SECS: i64 = 60;
+
+/// Default time in seconds for which the results of `GET /sources` and `GET /count` are cached, or 0
+/// to not cache them.
+const DEFAULT_CACHE_TTL_SECS: u64 = 10;
+
+/// Default period between runs of the retention task, in seconds.
+const DEFAULT_RETENTION_INTERVAL_SECS: u64 = 3600;
+
+/// Default time in seconds after which a silent source is considered stale by `GET /sources/status`.
+const DEFAULT_STALE_AFTER_SECS: i64 = 3600;
+
+/// Default greatest accuracy in metres of records of `good` quality.
+const DEFAULT_QUALITY_GOOD_M: f64 = 20.0;
+
+/// Default greatest accuracy in metres of records of `fair` quality, less accurate ones being `poor`.
+const DEFAULT_QUALITY_FAIR_M: f64 = 100.0;
+
+/// Default time in milliseconds after which reverse geocoding lookups are given up.
+const DEFAULT_GEOCODER_TIMEOUT_MS: u64 = 2000;
+
+/// Default number of records returned by `GET /` when no `limit` is given.
+const DEFAULT_PAGE_SIZE: i64 = 1000;
+
+/// Default maximum number of records returned by `GET /`, even when a larger `limit` is given.
+const DEFAULT_MAX_PAGE_SIZE: i64 = 10000;
+
+/// Header reporting the effective page size of `GET /` responses.
+const X_PAGE_SIZE: HeaderName = HeaderName::from_static("x-page-size");
+
+/// Default maximum number of records returned by the endpoints without pagination, such as exports.
+const DEFAULT_MAX_RESULTS: i64 = 50000;
+
+/// Header set to `true` when more records matched than were returned.
+const X_RESULT_TRUNCATED: HeaderName = HeaderName::from_static("x-result-truncated");
+
+/// Header carrying the key making retries of `POST /` idempotent.
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Maximum number of sets of filters run by a single `POST /query`.
+const MAX_QUERIES: usize = 10;
+
+/// Maximum length of an idempotency key.
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Default time in seconds for which an idempotency key is remembered.
+const DEFAULT_IDEMPOTENCY_TTL_SECS: i64 = 24 * 60 * 60;
+
+/// Default tolerance of `GET /simplified` in metres.
+const DEFAULT_SIMPLIFY_TOLERANCE_M: f64 = 10.0;
+
+/// Default number of decimal places the cells of `GET /heatmap` are rounded to, about 110 m.
+const DEFAULT_HEATMAP_PRECISION: u32 = 3;
+
+/// Maximum number of decimal places of `GET /heatmap`, about 11 cm, beyond which cells are points.
+const MAX_HEATMAP_PRECISION: u32 = 6;
+
+/// Default width and height of `GET /render.png`, in pixels.
+const DEFAULT_RENDER_SIZE: (u32, u32) = (640, 480);
+
+/// Maximum width and height of `GET /render.png`, in pixels.
+const MAX_RENDER_SIZE: u32 = 2048;
+
+/// Maximum number of decimal places of `COORD_PRECISION`, beyond which `f64` has no digits left.
+const MAX_COORD_PRECISION: u32 = 15;
+
+/// How far into the future a client-supplied `created_at` may be, to allow for clock skew.
+const MAX_FUTURE_SKEW: TimeDelta = TimeDelta::days(1);
+
+/// Number of inserted records buffered for live subscribers before slow ones start missing some.
+const UPDATES_CAPACITY: usize = 256;
+
+/// Interval between pings sent to WebSocket clients.
+const WS_PING_INTERVAL: Duration = Duration::from_secs(30);
+
 /// Custom deserializer for empty strings
-/// 
+///
 /// This is to ensure proper parsing and writing SQL queries, sicne empty strings imply
 /// the users does not want to filter by that field, not actually filtering by an empty string.
+/// Surrounding whitespace is trimmed, so blank strings count as empty too. JSON numbers and booleans
+/// are parsed as if they were strings, so the same filters can be sent in a JSON body.
 fn empty_string_as_none<'de, D, T>(de: D) -> Result<Option<T>, D::Error>
 where
     D: Deserializer<'de>,
     T: FromStr,
     T::Err: Display,
 {
-    let opt = Option::<String>::deserialize(de)?;
-    match opt.as_deref() {
+    let opt = de.deserialize_any(ScalarVisitor)?;
+    match opt.as_deref().map(str::trim) {
         None | Some("") => Ok(None),
         Some(s) => T::from_str(s)
             .map(Some)
@@ -42,162 +231,3740 @@ where
     }
 }
 
+/// Visitor reading any scalar as an optional string, for `empty_string_as_none`.
+struct ScalarVisitor;
+
+impl<'de> de::Visitor<'de> for ScalarVisitor {
+    type Value = Option<String>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a string, number or boolean")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(Some(v.to_string()))
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+        Ok(Some(v.to_string()))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(Some(v.to_string()))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(Some(v.to_string()))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(Some(v.to_string()))
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, de: D) -> Result<Self::Value, D::Error> {
+        de.deserialize_any(self)
+    }
+}
+
+/// Custom deserializer for optional timestamps
+///
+/// Like `empty_string_as_none`, but timestamps may be given in RFC 3339 with any offset and are
+/// converted to naive UTC, which is how `created_at` is stored.
+fn empty_string_as_utc<'de, D>(de: D) -> Result<Option<NaiveDateTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let opt = Option::<String>::deserialize(de)?;
+    match opt.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(s) => timestamp::parse(s)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+    }
+}
+
+/// Custom deserializer normalising comma-separated tags, so that `" hike, ,commute "` is stored as
+/// `"hike,commute"`. No tags at all is treated as `None`.
+fn tag_list<'de, D>(de: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let opt = Option::<String>::deserialize(de)?;
+    let tags: Vec<&str> = opt.as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    Ok(if tags.is_empty() { None } else { Some(tags.join(",")) })
+}
+
+/// Custom deserializer trimming surrounding whitespace, so that `" phone "` is stored as `"phone"`.
+fn trimmed_string<'de, D>(de: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(de)?;
+    Ok(s.trim().to_string())
+}
+
+/// Helper function to read an optional environment variable, falling back to `default` when it is
+/// unset or empty.
+fn env_or<T>(name: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(v) if !v.is_empty() => v.parse()
+            .with_context(|| format!("Invalid {} {:?}", name, v)),
+        _ => Ok(default),
+    }
+}
+
 /// Helper function to sanitise strings.
-fn sanitise_string(s: &str) -> bool {
-    SANITISATION_REGEX.is_match(s)
+///
+/// Surrounding whitespace is ignored, as sources are trimmed when deserialised, and everything not
+/// allowed by the regex is rejected. The reason for rejecting a string is returned so it can be
+/// reported back to the client.
+fn sanitise_string(s: &str) -> Result<(), SourceError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(SourceError::Empty);
+    }
+    if s.len() > MAX_SOURCE_LEN {
+        return Err(SourceError::TooLong { max: MAX_SOURCE_LEN });
+    }
+    if !SANITISATION_REGEX.is_match(s) {
+        return Err(SourceError::DisallowedCharacters);
+    }
+    Ok(())
+}
+
+/// Helper function to validate coordinates.
+///
+/// Latitude must be within [-90, 90] and longitude within [-180, 180]. NaN and infinite values are
+/// rejected as well.
+fn validate_coords(lat: f64, lon: f64) -> Result<(), String> {
+    if !lat.is_finite() || !(-90.0..=90.0).contains(&lat) {
+        return Err(format!("Invalid latitude {}: must be within [-90, 90]", lat));
+    }
+    if !lon.is_finite() || !(-180.0..=180.0).contains(&lon) {
+        return Err(format!("Invalid longitude {}: must be within [-180, 180]", lon));
+    }
+    Ok(())
+}
+
+/// Helper function to cut records fetched with one more than `limit` back to `limit`.
+///
+/// Returns the `X-Result-Truncated` header to send when there was indeed more, so that clients know
+/// to narrow their filters or fetch the next page.
+fn truncate_results(data: &mut Vec<DbLocData>, limit: i64) -> Option<[(HeaderName, &'static str); 1]> {
+    let limit = usize::try_from(limit).unwrap_or(0);
+    if data.len() > limit {
+        data.truncate(limit);
+        Some([(X_RESULT_TRUNCATED, "true")])
+    } else {
+        None
+    }
+}
+
+/// Helper function to compute the entity tag of a response to `GET /`.
+///
+/// It hashes the records along with the query, format and page, so it changes whenever a matching
+/// record is added, updated or deleted. Responses may be compressed, so the tag is weak.
+fn entity_tag(query: &GetLocQuery, format: OutputFormat, data: &[DbLocData], page: Option<Page>) -> String {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(format!("{:?} {:?} {:?} {:?}", query, format, data, page).as_bytes());
+    format!("W/\"{:016x}\"", hasher.finish())
+}
+
+/// Helper function to return `data` as JSON, wrapped in an envelope along with `page` when set.
+fn json_page<T: Serialize>(data: Vec<T>, page: Option<Page>) -> Response {
+    match page {
+        Some(page) => Json(Paginated { data, page }).into_response(),
+        None => Json(data).into_response(),
+    }
+}
+
+/// Helper function to return `data` as JSON like `json_page`, keeping only `fields` of each record
+/// when set.
+fn json_fields<T: Serialize>(data: Vec<T>, page: Option<Page>, fields: Option<&FieldList>) -> Response {
+    let Some(FieldList(fields)) = fields else {
+        return json_page(data, page);
+    };
+    let projected: Vec<serde_json::Map<String, serde_json::Value>> = data
+        .iter()
+        .map(|record| match serde_json::to_value(record) {
+            Ok(serde_json::Value::Object(mut record)) => {
+                record.retain(|key, _| fields.contains(key));
+                record
+            }
+            _ => serde_json::Map::new(),
+        })
+        .collect();
+    json_page(projected, page)
+}
+
+/// Helper function to tell whether the `If-None-Match` header lists `etag`, in which case the
+/// client already has the response.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers.get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag))
+}
+
+/// Helper function to validate the time range of a query.
+///
+/// Both bounds are inclusive, so a range with `from` after `to` can never match anything and is
+/// rejected rather than silently returning no records.
+fn validate_time_range(from: Option<NaiveDateTime>, to: Option<NaiveDateTime>) -> Result<(), ApiError> {
+    match (from, to) {
+        (Some(from), Some(to)) if from > to => Err(ApiError::InvalidTimestamp(format!(
+            "Invalid time range: from {} is after to {}",
+            timestamp::format(&from),
+            timestamp::format(&to),
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Helper function to validate location data sent by the client.
+///
+/// Checks the source string, the coordinates, the optional metadata and timestamp, and is shared by
+/// single and bulk inserts.
+fn validate_loc_data(data: &PostLocData) -> Result<(), ApiError> {
+    sanitise_string(&data.source)?;
+    if let Some(ref provider) = data.provider {
+        sanitise_string(provider).map_err(|e| ApiError::InvalidParameter(format!("Invalid provider: {}", e)))?;
+    }
+    for tag in data.tags.iter().flat_map(|tags| tags.split(',')) {
+        sanitise_string(tag).map_err(|e| ApiError::InvalidParameter(format!("Invalid tag {:?}: {}", tag, e)))?;
+    }
+    if let Some(battery) = data.battery {
+        if !(0.0..=100.0).contains(&battery) {
+            return Err(ApiError::InvalidParameter(format!("Invalid battery {}: must be between 0 and 100", battery)));
+        }
+    }
+    if let Some(created_at) = data.created_at {
+        if created_at > Utc::now().naive_utc() + MAX_FUTURE_SKEW {
+            let message = format!("Invalid created_at {}: too far in the future", created_at);
+            return Err(ApiError::InvalidTimestamp(message));
+        }
+    }
+    validate_coords(data.latitude, data.longitude).map_err(ApiError::InvalidCoordinates)
+}
+
+/// Helper function to fill in `default` as the source of `data` when it was sent without one.
+///
+/// Records still without a source are then rejected by `validate_loc_data`.
+fn with_default_source(data: &PostLocData, default: Option<&str>) -> PostLocData {
+    let mut data = data.clone();
+    if let Some(default) = default.filter(|_| data.source.is_empty()) {
+        data.source = default.to_string();
+    }
+    data
+}
+
+/// Helper function to round the coordinates of `data` to `precision` decimal places, when set.
+///
+/// Digits beyond what GPS can tell only waste space, and rounding them away makes repeated readings
+/// of the same spot identical.
+fn round_coords(data: &PostLocData, precision: Option<u32>) -> PostLocData {
+    let mut data = data.clone();
+    if let Some(precision) = precision {
+        let factor = 10f64.powi(precision as i32);
+        data.latitude = (data.latitude * factor).round() / factor;
+        data.longitude = (data.longitude * factor).round() / factor;
+    }
+    data
 }
 
 /// Data structure for location data returned from/inserted into the database.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// `created_at` is stored in UTC and serialised as RFC 3339 with a `Z` suffix, as is `deleted_at`,
+/// which is only set on records deleted with a `DELETE` endpoint and not purged yet.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, ToSchema)]
 struct DbLocData {
     id: i64,
     source: String,
     latitude: f64,
     longitude: f64,
+    altitude: Option<f64>,
+    accuracy: Option<f64>,
+    speed: Option<f64>,
+    battery: Option<f64>,
+    provider: Option<String>,
+    tags: Option<String>,
+    #[serde(with = "timestamp")]
     created_at: NaiveDateTime,
+    #[serde(default, with = "timestamp::option")]
+    deleted_at: Option<NaiveDateTime>,
 }
 
 /// Data structure for location data sent by the client to `POST /` endpoint.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// `source` may be left out or empty when the instance has a `DEFAULT_SOURCE`, which is then used.
+/// `altitude`, `accuracy` (in metres) and `speed` (in m/s) are only stored for sources that report
+/// them, as are the `battery` level (in percent) and the `provider` of the fix such as `gps` or
+/// `network`, which is sanitised like `source`. `tags` are comma-separated labels such as
+/// `commute,hike`, each sanitised like `source`. `created_at` is only needed when replaying readings
+/// captured earlier; when absent the database assigns the current time. It may carry any UTC offset
+/// and is converted to UTC.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 struct PostLocData {
+    #[serde(default, deserialize_with = "trimmed_string")]
     source: String,
     latitude: f64,
     longitude: f64,
+    altitude: Option<f64>,
+    accuracy: Option<f64>,
+    speed: Option<f64>,
+    battery: Option<f64>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    provider: Option<String>,
+    #[serde(default, deserialize_with = "tag_list")]
+    tags: Option<String>,
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    created_at: Option<NaiveDateTime>,
 }
 
-/// Data structure for query parameters sent by the client to `GET /` endpoint.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct GetLocQuery {
-    #[serde(default, deserialize_with = "empty_string_as_none")]
-    source: Option<String>,
+/// Body of `POST /`, either a single location record or an array of them.
+///
+/// It is deserialised by hand rather than as an untagged enum, so that an invalid record is
+/// reported with the reason it was rejected rather than as matching no variant.
+#[derive(Debug, Clone, ToSchema)]
+#[serde(untagged)] // Only makes the specification describe the body as one of the variants
+enum PostBody {
+    One(PostLocData),
+    Many(Vec<PostLocData>),
+}
 
-    #[serde(default, deserialize_with = "empty_string_as_none")]
-    from: Option<NaiveDateTime>,
+impl<'de> Deserialize<'de> for PostBody {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PostBodyVisitor;
 
-    #[serde(default, deserialize_with = "empty_string_as_none")]
-    to: Option<NaiveDateTime>,
+        impl<'de> de::Visitor<'de> for PostBodyVisitor {
+            type Value = PostBody;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a location record or an array of location records")
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, map: A) -> Result<PostBody, A::Error> {
+                PostLocData::deserialize(de::value::MapAccessDeserializer::new(map)).map(PostBody::One)
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, seq: A) -> Result<PostBody, A::Error> {
+                Vec::deserialize(de::value::SeqAccessDeserializer::new(seq)).map(PostBody::Many)
+            }
+        }
+
+        deserializer.deserialize_any(PostBodyVisitor)
+    }
 }
 
-/// Application state.
-/// 
-/// Contains the database pool. It may also contains more items in the future.
-#[derive(Debug, Clone)]
-struct App {
-    database_pool: SqlitePool,
+/// Sort order of records returned by `GET /`.
+///
+/// Sort directions cannot be bound as SQL parameters, so only these whitelisted values are ever
+/// written into the query.
+#[derive(Debug, Clone, Copy, Default, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
 }
 
-/// Handler for `POST /` endpoint.
-/// 
-/// It will insert a new location record into the database as requested by the client.
-async fn post_location(State(app): State<App>, Json(data): Json<PostLocData>) -> impl IntoResponse {
-    // Log the request
-    tracing::info!("POST / <- {:?}", data);
+impl SortOrder {
+    /// SQL keyword for this sort order.
+    fn as_sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
 
-    // Sanitise the source string
-    if !sanitise_string(&data.source) {
-        return (StatusCode::BAD_REQUEST, "Invalid source").into_response();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            _ => Err(format!("Invalid order {:?}: must be \"asc\" or \"desc\"", s)),
+        }
     }
+}
 
-    // Insert the record into the database
-    let result = sqlx::query_as!(
-        DbLocData,
-        "INSERT INTO locations (source, latitude, longitude) VALUES (?, ?, ?) RETURNING *",
-        data.source,
-        data.latitude,
-        data.longitude,
-    ).fetch_one(&app.database_pool).await;
+/// Output format of records returned by `GET /`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    #[default]
+    Json,
+    GeoJson,
+    Csv,
+    Ndjson,
+    Polyline,
+}
 
-    // Verify the result and return the appropriate response
-    if let Ok(data) = result {
-        tracing::info!("Record added: {:?}", data);
-        return (StatusCode::OK, "Record added").into_response();
-    } else {
-        tracing::error!("Cannot add record: {:?}", result);
-        return (StatusCode::INTERNAL_SERVER_ERROR, "No record added").into_response();
+impl OutputFormat {
+    /// Pick the format preferred by an `Accept` header, if it names any supported media type.
+    /// 
+    /// Media types are weighed by their `q` parameter, and the first one wins among equals, as
+    /// most clients list their preferences in order.
+    fn from_accept(accept: &str) -> Option<Self> {
+        let mut best: Option<(OutputFormat, f32)> = None;
+        for range in accept.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let format = match params.next().unwrap_or_default().to_ascii_lowercase().as_str() {
+                "application/json" => OutputFormat::Json,
+                "application/geo+json" => OutputFormat::GeoJson,
+                "text/csv" => OutputFormat::Csv,
+                "application/x-ndjson" => OutputFormat::Ndjson,
+                _ => continue,
+            };
+            let q = params
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((format, q));
+            }
+        }
+        best.map(|(format, _)| format)
     }
 }
 
-/// Handler for `GET /` endpoint.
-/// 
-/// It will fetch all location records from the database as requested by the client. Optional parameters
-/// are used to filter the records.
-async fn get_all_locations(State(app): State<App>, Query(query): Query<GetLocQuery>) -> impl IntoResponse {
-    // Log the request
-    tracing::info!("GET / <- {:?}", query);
+impl FromStr for OutputFormat {
+    type Err = String;
 
-    // Sanitise the source string
-    if let Some(ref s) = query.source {
-        if !sanitise_string(s) {
-            return (StatusCode::BAD_REQUEST, "Invalid source").into_response();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "geojson" => Ok(OutputFormat::GeoJson),
+            "csv" => Ok(OutputFormat::Csv),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "polyline" => Ok(OutputFormat::Polyline),
+            _ => Err(format!(
+                "Invalid format {:?}: must be \"json\", \"geojson\", \"csv\", \"ndjson\" or \"polyline\"",
+                s,
+            )),
         }
     }
+}
 
-    // Fetch the records from the database
-    let result = sqlx::query_as!(
-        DbLocData,
-        r#"
-        SELECT * FROM locations
-        WHERE 
-            (?1 IS NULL OR source = ?1)
-            AND (?2 IS NULL OR created_at >= ?2)
-            AND (?3 IS NULL OR created_at <= ?3)
-        "#,
-        query.source,
-        query.from,
-        query.to,
-    )
-    .fetch_all(&app.database_pool)
-    .await;
-    
-    // Verify the result and return the appropriate response
-    if let Ok(data) = result {
-        tracing::info!("{} records fetched: {:?}", data.len(), query);
-        return Json(data).into_response();
-    } else {
-        tracing::error!("Cannot fetch records: {:?}", result);
-        return (StatusCode::INTERNAL_SERVER_ERROR, "No records fetched").into_response();
+/// Comma-separated list of sources, as accepted by the `source` filter of `GET /`.
+#[derive(Debug, Clone, Serialize)]
+struct SourceList(Vec<String>);
+
+impl FromStr for SourceList {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(SourceList(s.split(',').map(|s| s.trim().to_string()).collect()))
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Load environment variables
-    #[cfg(debug_assertions)]
-    dotenvy::dotenv().context("Cannot load .env file")?;
+/// Fields of the JSON records of `GET /` that can be selected with `fields`.
+const SELECTABLE_FIELDS: [&str; 14] = [
+    "id",
+    "source",
+    "latitude",
+    "longitude",
+    "altitude",
+    "accuracy",
+    "speed",
+    "battery",
+    "provider",
+    "tags",
+    "created_at",
+    "deleted_at",
+    "place",
+    "quality",
+];
 
-    // Set up tracing
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env())
-        .init();
+/// Comma-separated list of fields, as accepted by the `fields` parameter of `GET /`.
+///
+/// Only the names of `SELECTABLE_FIELDS` are accepted, so that unknown names are reported rather
+/// than silently dropping every field.
+#[derive(Debug, Clone, Serialize)]
+struct FieldList(Vec<String>);
 
-    // Open database connection
-    let database_url = std::env::var("DATABASE_URL")
-        .context("DATABASE_URL is not set")?;
-    let database_pool = SqlitePool::connect(&database_url)
-        .await
-        .context("Cannot connect to database")?;
+impl FromStr for FieldList {
+    type Err = String;
 
-    // Initialise application
-    let state = App { database_pool };
-    let app = Router::new()
-        .route("/", get(get_all_locations))
-        .route("/", post(post_location))
-        .layer(TraceLayer::new_for_http())
-        .with_state(state);
-    let port = std::env::var("PORT")
-        .context("PORT is not set")?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<String> = s.split(',').map(|s| s.trim().to_string()).collect();
+        match fields.iter().find(|field| !SELECTABLE_FIELDS.contains(&field.as_str())) {
+            Some(field) => Err(format!("Unknown field {:?}: must be among {}", field, SELECTABLE_FIELDS.join(","))),
+            None => Ok(FieldList(fields)),
+        }
+    }
+}
 
-    // Serve application
-    let addr = format!("0.0.0.0:{}", port);
-    tracing::info!("Starting server on {}", addr);
+/// Data structure for query parameters sent by the client to `GET /` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct GetLocQuery {
+    /// Comma-separated sources to keep.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    #[param(value_type = Option<String>)]
+    source: Option<SourceList>,
 
-    let listener = TcpListener::bind(&addr)
-        .await
-        .context("Cannot bind to port")?;
-    axum::serve(listener, app)
-        .await
-        .context("Cannot start server")?;
+    /// Earliest `created_at` to keep, inclusive.
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    from: Option<NaiveDateTime>,
 
-    Ok(())
+    /// Latest `created_at` to keep, inclusive.
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    to: Option<NaiveDateTime>,
+
+    /// Day of `created_at` to keep, in UTC, instead of `from` and `to`.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    date: Option<NaiveDate>,
+
+    /// Maximum number of records to return.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    limit: Option<i64>,
+
+    /// Number of records to skip.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    offset: Option<i64>,
+
+    /// Order of the records by `created_at`.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    order: Option<SortOrder>,
+
+    /// Return the given number of most recent records, newest first, instead of `limit`, `offset`
+    /// and `order`.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    last: Option<i64>,
+
+    /// Southern edge of the bounding box, used along with the other edges.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    min_lat: Option<f64>,
+
+    /// Northern edge of the bounding box.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    max_lat: Option<f64>,
+
+    /// Western edge of the bounding box.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    min_lon: Option<f64>,
+
+    /// Eastern edge of the bounding box.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    max_lon: Option<f64>,
+
+    /// Maximum accuracy in metres; records of unknown accuracy are kept.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    max_accuracy: Option<f64>,
+
+    /// Minimum quality, `good`, `fair` or `poor`; records of unknown accuracy are dropped.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    min_quality: Option<Quality>,
+
+    /// Only keep records with a greater id.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    since_id: Option<i64>,
+
+    /// Only keep records with this id or a smaller one.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    max_id: Option<i64>,
+
+    /// Format of the response.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    format: Option<OutputFormat>,
+
+    /// Add the name of the place of each record to JSON responses, when a geocoder is configured.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    enrich: Option<bool>,
+
+    /// Wrap JSON responses in `{"data": [...], "page": {...}}`, with the total number of matches.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    paginated: Option<bool>,
+
+    /// Also return the records deleted but not purged yet.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    include_deleted: Option<bool>,
+
+    /// Tag the records must carry, among their comma-separated tags.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    tag: Option<String>,
+
+    /// Comma-separated fields to keep in JSON records, such as `id,latitude,longitude`.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    #[param(value_type = Option<String>)]
+    fields: Option<FieldList>,
+}
+
+impl GetLocQuery {
+    /// Validate the filter parameters shared by `GET /` and `GET /count`.
+    fn validate_filters(&self) -> Result<(), ApiError> {
+        // Sanitise the source strings, rejecting the whole request if any of them is invalid
+        if let Some(SourceList(ref sources)) = self.source {
+            for s in sources {
+                sanitise_string(s)?;
+            }
+        }
+        if let Some(ref tag) = self.tag {
+            sanitise_string(tag).map_err(|e| ApiError::InvalidParameter(format!("Invalid tag {:?}: {}", tag, e)))?;
+        }
+
+        // The bounding box must be supplied as a whole or not at all
+        let bbox = [self.min_lat, self.max_lat, self.min_lon, self.max_lon];
+        if bbox.iter().any(Option::is_some) && !bbox.iter().all(Option::is_some) {
+            return Err(ApiError::InvalidParameter(
+                "min_lat, max_lat, min_lon and max_lon must be used together".to_string(),
+            ));
+        }
+
+        // Ids are never negative
+        if self.since_id.is_some_and(|id| id < 0) || self.max_id.is_some_and(|id| id < 0) {
+            return Err(ApiError::InvalidParameter("Invalid since_id or max_id".to_string()));
+        }
+
+        // A whole day replaces the time range
+        if self.date.is_some() && (self.from.is_some() || self.to.is_some()) {
+            return Err(ApiError::InvalidParameter("date cannot be used with from or to".to_string()));
+        }
+
+        validate_time_range(self.from, self.to)
+    }
+
+    /// Validate the pagination parameters, returning the limit and offset to use.
+    /// 
+    /// The limit is clamped to `max_page_size` rather than rejected, so that existing clients keep
+    /// working. `last` is a page of its own, from the newest record.
+    fn page(&self, default_page_size: i64, max_page_size: i64) -> Result<(i64, i64), ApiError> {
+        if let Some(last) = self.last {
+            if self.limit.is_some() || self.offset.is_some() || self.order.is_some() {
+                return Err(ApiError::InvalidParameter(
+                    "last cannot be used with limit, offset or order".to_string(),
+                ));
+            }
+            if last < 0 {
+                return Err(ApiError::InvalidParameter("Invalid last".to_string()));
+            }
+            return Ok((last.min(max_page_size), 0));
+        }
+
+        let limit = self.limit.unwrap_or(default_page_size);
+        let offset = self.offset.unwrap_or(0);
+        if limit < 0 || offset < 0 {
+            return Err(ApiError::InvalidParameter("Invalid limit or offset".to_string()));
+        }
+        Ok((limit.min(max_page_size), offset))
+    }
+
+    /// Build the query fetching up to `limit` matching records from `offset`, in the requested order,
+    /// or newest first with `last`.
+    fn select(&self, limit: i64, offset: i64, quality: QualityThresholds) -> QueryBuilder<'static, Sqlite> {
+        let order = if self.last.is_some() { SortOrder::Desc } else { self.order.unwrap_or_default() };
+        let mut builder = QueryBuilder::new("SELECT * FROM locations");
+        self.push_filters(&mut builder, quality);
+        builder
+            .push(format_args!(" ORDER BY created_at {}", order.as_sql()))
+            .push(" LIMIT ")
+            .push_bind(limit)
+            .push(" OFFSET ")
+            .push_bind(offset);
+        builder
+    }
+
+    /// Append a `WHERE` clause matching the filter parameters to `builder`.
+    /// 
+    /// Every handler accepting these filters builds its query through here, so they can't drift apart.
+    /// `min_quality` is matched against the accuracies bounding the `quality` buckets.
+    fn push_filters(&self, builder: &mut QueryBuilder<'_, Sqlite>, quality: QualityThresholds) {
+        builder.push(" WHERE 1 = 1");
+        if let Some(SourceList(ref sources)) = self.source {
+            builder.push(" AND source IN (");
+            let mut separated = builder.separated(", ");
+            for source in sources {
+                separated.push_bind(source.clone());
+            }
+            builder.push(")");
+        }
+        if let Some(from) = self.from {
+            builder.push(" AND created_at >= ").push_bind(from);
+        }
+        if let Some(to) = self.to {
+            builder.push(" AND created_at <= ").push_bind(to);
+        }
+        if let Some(date) = self.date {
+            let start = date.and_time(NaiveTime::MIN);
+            builder
+                .push(" AND created_at >= ")
+                .push_bind(start)
+                .push(" AND created_at < ")
+                .push_bind(start + TimeDelta::days(1));
+        }
+        if let (Some(min_lat), Some(max_lat), Some(min_lon), Some(max_lon)) =
+            (self.min_lat, self.max_lat, self.min_lon, self.max_lon)
+        {
+            builder
+                .push(" AND latitude BETWEEN ")
+                .push_bind(min_lat)
+                .push(" AND ")
+                .push_bind(max_lat)
+                .push(" AND longitude BETWEEN ")
+                .push_bind(min_lon)
+                .push(" AND ")
+                .push_bind(max_lon);
+        }
+        if let Some(max_accuracy) = self.max_accuracy {
+            builder.push(" AND (accuracy IS NULL OR accuracy <= ").push_bind(max_accuracy).push(")");
+        }
+        if let Some(min_quality) = self.min_quality {
+            match quality.max_accuracy(min_quality) {
+                Some(max_accuracy) => builder.push(" AND accuracy <= ").push_bind(max_accuracy),
+                None => builder.push(" AND accuracy IS NOT NULL"),
+            };
+        }
+        if let Some(since_id) = self.since_id {
+            builder.push(" AND id > ").push_bind(since_id);
+        }
+        if let Some(max_id) = self.max_id {
+            builder.push(" AND id <= ").push_bind(max_id);
+        }
+        if let Some(ref tag) = self.tag {
+            // Matched as a whole tag, delimited by commas, rather than with LIKE and its wildcards
+            builder
+                .push(" AND instr(',' || tags || ',', ',' || ")
+                .push_bind(tag.trim().to_string())
+                .push(" || ',') > 0");
+        }
+        if !self.include_deleted.unwrap_or(false) {
+            builder.push(" AND deleted_at IS NULL");
+        }
+    }
+}
+
+/// Data structure for query parameters sent by the client to track export and statistics endpoints.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TrackQuery {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    source: Option<String>,
+
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    from: Option<NaiveDateTime>,
+
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    to: Option<NaiveDateTime>,
+}
+
+impl TrackQuery {
+    /// Validate the filter parameters.
+    fn validate_filters(&self) -> Result<(), ApiError> {
+        if let Some(ref s) = self.source {
+            sanitise_string(s)?;
+        }
+        validate_time_range(self.from, self.to)
+    }
+}
+
+/// Data structure for query parameters sent by the client to `GET /stats/distance` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DistanceQuery {
+    source: String,
+
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    from: Option<NaiveDateTime>,
+
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    to: Option<NaiveDateTime>,
+
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    max_segment_km: Option<f64>,
+}
+
+/// Data structure for query parameters sent by the client to `GET /simplified` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SimplifyQuery {
+    source: String,
+
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    from: Option<NaiveDateTime>,
+
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    to: Option<NaiveDateTime>,
+
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    tolerance_m: Option<f64>,
+}
+
+/// Data structure for query parameters sent by the client to `GET /heatmap` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HeatmapQuery {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    source: Option<String>,
+
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    from: Option<NaiveDateTime>,
+
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    to: Option<NaiveDateTime>,
+
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    precision: Option<u32>,
+}
+
+/// Data structure for query parameters sent by the client to `GET /render.png` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RenderQuery {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    source: Option<String>,
+
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    from: Option<NaiveDateTime>,
+
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    to: Option<NaiveDateTime>,
+
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    width: Option<u32>,
+
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    height: Option<u32>,
+}
+
+/// Data structure for a cell of the grid and its number of records, returned by `GET /heatmap`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HeatmapCell {
+    lat: f64,
+    lon: f64,
+    count: i64,
+}
+
+/// Data structure for the number of records of a source on a given day, returned by
+/// `GET /stats/daily`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DailyCount {
+    day: NaiveDate,
+    source: String,
+    count: i64,
+}
+
+/// Data structure for query parameters sent by the client to `GET /sources` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SourcesQuery {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    counts: Option<bool>,
+}
+
+/// Data structure for a source and its number of records, returned by `GET /sources`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SourceCount {
+    source: String,
+    count: i64,
+}
+
+/// Data structure for the freshness of a source, returned by `GET /sources/status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SourceStatus {
+    source: String,
+    #[serde(with = "timestamp")]
+    last_seen: NaiveDateTime,
+    age_secs: i64,
+    stale: bool,
+}
+
+/// Data structure for query parameters sent by the client to `GET /latest` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LatestQuery {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    source: Option<String>,
+}
+
+/// Data structure for query parameters sent by the client to `DELETE /locations` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PurgeQuery {
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    before: Option<NaiveDateTime>,
+
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    source: Option<String>,
+}
+
+/// Data structure for query parameters sent by the client to `GET /locations/:id` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DeletedQuery {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    include_deleted: Option<bool>,
+}
+
+/// Data structure for query parameters sent by the client to `DELETE /purge` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PurgeDeletedQuery {
+    #[serde(default, deserialize_with = "empty_string_as_utc")]
+    before: Option<NaiveDateTime>,
+}
+
+/// Data structure for query parameters sent by the client to `DELETE /sources/:source` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ConfirmQuery {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    confirm: Option<bool>,
+}
+
+/// Data structure for the body sent by the client to `POST /sources/:source/rename` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RenameSource {
+    #[serde(deserialize_with = "trimmed_string")]
+    new: String,
+
+    #[serde(default)]
+    merge: bool,
+}
+
+/// Outcome of renaming a source inside its transaction.
+enum Renaming {
+    Renamed(u64),
+    Exists,
+}
+
+/// Data structure for query parameters sent by the client to `GET /stream` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StreamQuery {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    source: Option<String>,
+}
+
+/// Data structure for query parameters sent by the client to `GET /nearby` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NearbyQuery {
+    lat: f64,
+    lon: f64,
+    radius_km: f64,
+}
+
+/// Data structure for query parameters sent by the client to `GET /locations/:id/nearby` endpoint.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RadiusQuery {
+    radius_km: f64,
+}
+
+/// Data structure for a location record returned by `GET /nearby`, along with its distance from
+/// the requested point.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NearbyLocData {
+    #[serde(flatten)]
+    location: DbLocData,
+    distance_km: f64,
+}
+
+/// Thresholds under which a reading is considered a duplicate of its source's last stored reading.
+#[derive(Debug, Clone, Copy)]
+struct Dedup {
+    distance_km: f64,
+    window: TimeDelta,
+}
+
+/// Outcome of [`insert_location`].
+#[derive(Debug, Clone)]
+enum Insertion {
+    /// The record was inserted.
+    Inserted(DbLocData),
+    /// The record repeated the given last record of its source and was dropped.
+    Deduplicated(DbLocData),
+    /// The idempotency key was already used by a concurrent request, which inserted the given record.
+    Replayed(DbLocData),
+}
+
+/// Data structure for a location record returned by `GET /?enrich=true`, along with the name of its
+/// place, which is `null` when unknown.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EnrichedLocData {
+    #[serde(flatten)]
+    location: DbLocData,
+    place: Option<String>,
+}
+
+/// Data structure for a JSON or NDJSON record returned by `GET /` and `POST /query`, along with its
+/// quality.
+#[derive(Debug, Clone, Serialize)]
+struct RatedLocData<T> {
+    #[serde(flatten)]
+    location: T,
+    quality: Quality,
+}
+
+/// Helper function to add their quality to location records.
+fn rate(data: Vec<DbLocData>, thresholds: QualityThresholds) -> Vec<RatedLocData<DbLocData>> {
+    data.into_iter()
+        .map(|location| RatedLocData { quality: thresholds.quality(location.accuracy), location })
+        .collect()
+}
+
+/// Data structure for the page of records returned by `GET /?paginated=true`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Paginated<T> {
+    data: Vec<T>,
+    page: Page,
+}
+
+/// Data structure for the position of a page among all matching records.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Page {
+    limit: i64,
+    offset: i64,
+    total: i64,
+    has_more: bool,
+}
+
+/// Application state, shared by every handler.
+#[derive(Debug, Clone)]
+struct App {
+    /// Pool of connections to the SQLite database.
+    database_pool: SqlitePool,
+    /// API key guarding write endpoints, if any.
+    api_key: Option<String>,
+    /// Handle rendering the metrics for `GET /metrics`.
+    metrics: PrometheusHandle,
+    /// Number of records returned by `GET /` when no `limit` is given.
+    default_page_size: i64,
+    /// Maximum number of records returned by `GET /`.
+    max_page_size: i64,
+    /// Maximum number of records returned by the endpoints without pagination.
+    max_results: i64,
+    /// Channel publishing inserted records to live subscribers.
+    updates: broadcast::Sender<DbLocData>,
+    /// Per-source limiter of writes, if enabled.
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Thresholds under which a reading repeating its source's last one is dropped, if enabled.
+    dedup: Option<Dedup>,
+    /// Cache of `GET /sources`.
+    sources_cache: Arc<TtlCache<Vec<SourceCount>>>,
+    /// Cache of `GET /count`.
+    count_cache: Arc<TtlCache<i64>>,
+    /// How long idempotency keys are remembered.
+    idempotency_ttl: TimeDelta,
+    /// Reverse geocoder adding place names, if configured.
+    geocoder: Option<Arc<Geocoder>>,
+    /// Path prefix the API is mounted under, empty at the root.
+    base_path: String,
+    /// Whether writes are forbidden.
+    read_only: bool,
+    /// Number of decimal places coordinates are rounded to, if any.
+    coord_precision: Option<u32>,
+    /// How long sources may stay silent before being stale.
+    stale_after: TimeDelta,
+    /// Naming of the fields of JSON responses.
+    field_case: FieldCase,
+    /// Source of records sent without one, if any.
+    default_source: Option<String>,
+    /// Maximum number of records of a batch.
+    max_batch_size: usize,
+    /// Accuracy thresholds of the quality of records.
+    quality: QualityThresholds,
+    /// Effective configuration served by `GET /debug/config`, when enabled.
+    debug_config: Option<Arc<EffectiveConfig>>,
+}
+
+/// Data structure for the effective configuration returned by `GET /debug/config`.
+///
+/// It is what the process made of its environment variables, defaults included. Secrets are left
+/// out: the database URL, which may carry credentials, is not included at all, and the API key and
+/// the geocoder URL, which may carry a key, are only said to be set.
+#[derive(Debug, Clone, Default, Serialize)]
+struct EffectiveConfig {
+    bind_addr: String,
+    port: u16,
+    tls: bool,
+    base_path: String,
+    log_format: String,
+    api_key_set: bool,
+    read_only: bool,
+    cors_allowed_origins: String,
+    db_max_connections: u32,
+    db_acquire_timeout_secs: u64,
+    db_busy_timeout_ms: u64,
+    db_connect_attempts: u32,
+    db_connect_retry_ms: u64,
+    db_create_if_missing: bool,
+    slow_query_ms: u64,
+    max_body_bytes: usize,
+    max_batch_body_bytes: usize,
+    max_batch_size: usize,
+    request_timeout_secs: u64,
+    default_page_size: i64,
+    max_page_size: i64,
+    max_results: i64,
+    metrics_refresh_secs: u64,
+    rate_limit_per_minute: u32,
+    rate_limit_burst: u32,
+    dedup_distance_m: f64,
+    dedup_window_secs: i64,
+    retention_days: i64,
+    retention_interval_secs: u64,
+    cache_ttl_secs: u64,
+    idempotency_ttl_secs: i64,
+    geocoder: bool,
+    coord_precision: Option<u32>,
+    stale_after_secs: i64,
+    quality_good_m: f64,
+    quality_fair_m: f64,
+    json_field_case: String,
+    default_source: Option<String>,
+}
+
+/// Middleware requiring a valid `X-API-Key` header.
+///
+/// It is applied to the write endpoints only. When no API key is configured, every request is let
+/// through.
+async fn require_api_key(State(app): State<App>, request: Request, next: Next) -> Response {
+    if !has_api_key(&app, request.headers()) {
+        tracing::warn!("Rejected {} {}: invalid API key", request.method(), request.uri());
+        return ApiError::Unauthorized.into_response();
+    }
+    next.run(request).await
+}
+
+/// Helper function to tell whether `headers` carry the configured API key, or none is configured.
+fn has_api_key(app: &App, headers: &HeaderMap) -> bool {
+    let Some(ref api_key) = app.api_key else {
+        return true;
+    };
+    let supplied = headers
+        .get("X-API-Key")
+        .and_then(|v| v.to_str().ok());
+    supplied == Some(api_key.as_str())
+}
+
+/// Middleware forbidding writes when the instance is read-only.
+///
+/// It is applied to the write endpoints only, ahead of the API key check, so that the data can be
+/// exposed publicly without any way to change it.
+async fn reject_writes(State(app): State<App>, request: Request, next: Next) -> Response {
+    if app.read_only {
+        tracing::warn!("Rejected {} {}: read-only mode", request.method(), request.uri());
+        return ApiError::ReadOnly.into_response();
+    }
+    next.run(request).await
+}
+
+/// Find the last record of the source of `data` when `data` repeats it within the thresholds.
+async fn find_duplicate(app: &App, data: &PostLocData, dedup: Dedup) -> Result<Option<DbLocData>, ApiError> {
+    let result = sqlx::query_as!(
+        DbLocData,
+        r#"
+        SELECT * FROM locations
+        WHERE source = ? AND deleted_at IS NULL
+        ORDER BY created_at DESC, id DESC
+        LIMIT 1
+        "#,
+        data.source,
+    ).fetch_optional(&app.database_pool).await;
+
+    let last = match result {
+        Ok(Some(last)) => last,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            tracing::error!("Cannot fetch last record: {:?}", e);
+            return Err(ApiError::Database("No record added"));
+        }
+    };
+
+    let created_at = data.created_at.unwrap_or_else(|| Utc::now().naive_utc());
+    let within_window = (created_at - last.created_at).abs() <= dedup.window;
+    let distance_km = geo::haversine_km((data.latitude, data.longitude), (last.latitude, last.longitude));
+    Ok((within_window && distance_km <= dedup.distance_km).then_some(last))
+}
+
+/// Validate and insert a location record, then publish it to live subscribers.
+///
+/// This is shared by `POST /` and `GET /ws`, so that records go through the same checks, rounding
+/// and rate limit whichever way they arrive. Batches are meant for replaying history and are not
+/// limited. When deduplication is enabled, readings repeating the last one of their source are
+/// dropped.
+///
+/// An idempotency `key` is stored in the same transaction as the record. When it is already taken,
+/// by a request that raced this one past [`find_idempotent`], the transaction is rolled back and
+/// the record of that request is returned instead.
+async fn insert_location(app: &App, data: &PostLocData, key: Option<&str>) -> Result<Insertion, ApiError> {
+    // Validate the location data
+    let data = &with_default_source(data, app.default_source.as_deref());
+    validate_loc_data(data)?;
+    let data = &round_coords(data, app.coord_precision);
+
+    // Throttle sources writing too often
+    if let Some(ref limiter) = app.rate_limiter {
+        if let Err(wait) = limiter.check(&data.source) {
+            tracing::warn!("Rate limited {}: retry in {:?}", data.source, wait);
+            return Err(ApiError::RateLimited { retry_after: wait.as_secs() + 1 });
+        }
+    }
+
+    // Drop readings repeating the last one of the source
+    if let Some(dedup) = app.dedup {
+        if let Some(last) = find_duplicate(app, data, dedup).await? {
+            tracing::info!("Record deduplicated against {:?}", last);
+            return Ok(Insertion::Deduplicated(last));
+        }
+    }
+
+    // Insert the record into the database, starting over when the database is locked. The insert
+    // runs in an explicit transaction, as without one SQLite may report the lock after the row was
+    // already written, which a retry would then duplicate.
+    let result = retry_busy(|| async {
+        let mut tx = app.database_pool.begin().await?;
+        let inserted = sqlx::query_as!(
+            DbLocData,
+            r#"
+            INSERT INTO locations (source, latitude, longitude, altitude, accuracy, speed, battery, provider, tags, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, COALESCE(?, CURRENT_TIMESTAMP))
+            RETURNING *
+            "#,
+            data.source,
+            data.latitude,
+            data.longitude,
+            data.altitude,
+            data.accuracy,
+            data.speed,
+            data.battery,
+            data.provider,
+            data.tags,
+            data.created_at,
+        ).fetch_one(&mut *tx).await?;
+
+        // Reserve the idempotency key, leaving the transaction to roll back when it is taken
+        if let Some(key) = key {
+            let reserved = sqlx::query!(
+                "INSERT INTO idempotency_keys (key, location_id) VALUES (?, ?)",
+                key,
+                inserted.id,
+            ).execute(&mut *tx).await;
+            match reserved {
+                Err(sqlx::Error::Database(ref e)) if e.is_unique_violation() => return Ok(None),
+                reserved => reserved?,
+            };
+        }
+        tx.commit().await?;
+        Ok(Some(inserted))
+    }).await;
+
+    // Verify the result and publish the record
+    match result {
+        Ok(Some(data)) => {
+            tracing::info!("Record added: {:?}", data);
+            // Sending only fails when nobody is listening, which is fine
+            let _ = app.updates.send(data.clone());
+            Ok(Insertion::Inserted(data))
+        }
+        Ok(None) => {
+            let key = key.expect("only idempotency keys can be taken");
+            match find_idempotent(app, key).await? {
+                Some(data) => Ok(Insertion::Replayed(data)),
+                None => {
+                    tracing::error!("Idempotency key {:?} taken by a record that is gone", key);
+                    Err(ApiError::Database("No record added"))
+                }
+            }
+        }
+        Err(e) => {
+            tracing::error!("Cannot add record: {:?}", e);
+            Err(ApiError::Database("No record added"))
+        }
+    }
+}
+
+/// Validate and insert many location records inside a single transaction, so either all of them
+/// are stored or none of them is.
+///
+/// This is shared by `POST /batch`, `POST /` with an array and `POST /import`. Batches are meant
+/// for replaying history, so they are neither rate limited, deduplicated nor published to live
+/// subscribers.
+async fn insert_batch(app: &App, items: &[PostLocData]) -> Result<Vec<DbLocData>, ApiError> {
+    // Validate every record before touching the database
+    let items: Vec<PostLocData> = items
+        .iter()
+        .map(|data| with_default_source(data, app.default_source.as_deref()))
+        .collect();
+    for (index, data) in items.iter().enumerate() {
+        if let Err(e) = validate_loc_data(data) {
+            return Err(ApiError::InvalidRecord { index, error: Box::new(e) });
+        }
+    }
+
+    // Insert the records into the database, starting over when the database is locked
+    let result: Result<Vec<DbLocData>, sqlx::Error> = retry_busy(|| async {
+        let mut tx = app.database_pool.begin().await?;
+        let mut inserted = Vec::with_capacity(items.len());
+        for data in &items {
+            let data = round_coords(data, app.coord_precision);
+            inserted.push(sqlx::query_as!(
+                DbLocData,
+                r#"
+                INSERT INTO locations (source, latitude, longitude, altitude, accuracy, speed, battery, provider, tags, created_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, COALESCE(?, CURRENT_TIMESTAMP))
+                RETURNING *
+                "#,
+                data.source,
+                data.latitude,
+                data.longitude,
+                data.altitude,
+                data.accuracy,
+                data.speed,
+                data.battery,
+                data.provider,
+                data.tags,
+                data.created_at,
+            )
+            .fetch_one(&mut *tx)
+            .await?);
+        }
+        tx.commit().await?;
+        Ok(inserted)
+    }).await;
+
+    // Verify the result
+    match result {
+        Ok(inserted) => {
+            tracing::info!("{} records added", inserted.len());
+            Ok(inserted)
+        }
+        Err(e) => {
+            tracing::error!("Cannot add records: {:?}", e);
+            Err(ApiError::Database("No records added"))
+        }
+    }
+}
+
+/// Helper function to tell whether a database error is transient, as the database was locked by
+/// another writer. Other errors, such as constraint violations, would only fail again.
+fn is_busy(e: &sqlx::Error) -> bool {
+    // Extended result codes carry the primary one, SQLITE_BUSY or SQLITE_LOCKED, in their low byte
+    let code = match e {
+        sqlx::Error::Database(e) => e.code().and_then(|code| code.parse::<i32>().ok()),
+        _ => None,
+    };
+    matches!(code.map(|code| code & 0xff), Some(5 | 6))
+}
+
+/// Helper function to run a write again, with exponential backoff, as long as it fails because the
+/// database is locked. It gives up after `MAX_BUSY_ATTEMPTS` attempts, or on any other error.
+///
+/// The write must run in a transaction, so that a failed attempt leaves nothing behind. The busy
+/// timeout already makes writers wait for each other, so this only covers bursts of writers
+/// outlasting it.
+async fn retry_busy<T, F, Fut>(mut write: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut delay = BUSY_RETRY_DELAY;
+    for _ in 1..MAX_BUSY_ATTEMPTS {
+        match write().await {
+            Err(e) if is_busy(&e) => {
+                // Jitter keeps writers that collided from retrying all at the same time again
+                let jitter = delay.mul_f64(RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64);
+                tracing::warn!("Database is locked, retrying in {:?}: {:?}", delay + jitter, e);
+                tokio::time::sleep(delay + jitter).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    write().await
+}
+
+/// Helper function to build the `Location` header pointing to a created record.
+fn location_header(app: &App, data: &DbLocData) -> [(HeaderName, String); 1] {
+    [(header::LOCATION, format!("{}/locations/{}", app.base_path, data.id))]
+}
+
+/// Find the record created by an earlier `POST /` carrying the same idempotency key.
+///
+/// Expired keys are forgotten on the way, so they can be reused, as are the keys of deleted records
+/// so that retries insert them again.
+async fn find_idempotent(app: &App, key: &str) -> Result<Option<DbLocData>, ApiError> {
+    let expired_before = Utc::now().naive_utc() - app.idempotency_ttl;
+    let result = retry_busy(|| async {
+        sqlx::query!(
+            r#"
+            DELETE FROM idempotency_keys
+            WHERE created_at < ? OR location_id IN (SELECT id FROM locations WHERE deleted_at IS NOT NULL)
+            "#,
+            expired_before,
+        ).execute(&app.database_pool).await
+    }).await;
+    if let Err(e) = result {
+        tracing::error!("Cannot delete expired idempotency keys: {:?}", e);
+        return Err(ApiError::Database("No record added"));
+    }
+
+    let result = sqlx::query_as!(
+        DbLocData,
+        r#"
+        SELECT * FROM locations
+        WHERE id = (SELECT location_id FROM idempotency_keys WHERE key = ?) AND deleted_at IS NULL
+        "#,
+        key,
+    ).fetch_optional(&app.database_pool).await;
+    result.map_err(|e| {
+        tracing::error!("Cannot fetch idempotent record: {:?}", e);
+        ApiError::Database("No record added")
+    })
+}
+
+/// Check the size of a batch sent by a client. Batches hold the write lock for as long as they run,
+/// so they are limited to `max_batch_size` records.
+fn check_batch_size(app: &App, items: &[PostLocData]) -> Result<(), ApiError> {
+    if items.is_empty() {
+        return Err(ApiError::InvalidParameter("Batch must not be empty".to_string()));
+    }
+    if items.len() > app.max_batch_size {
+        return Err(ApiError::BatchTooLarge { max: app.max_batch_size });
+    }
+    Ok(())
+}
+
+/// Handler for `POST /` endpoint.
+///
+/// It will insert a new location record into the database as requested by the client, and
+/// return the inserted record so the client can learn its `id` and `created_at`, along with its URL
+/// in the `Location` header. A deduplicated reading is answered with `200 OK` and the record it
+/// repeats instead.
+///
+/// An array of records is inserted as with `POST /batch`, returning the array of inserted records.
+///
+/// With an `Idempotency-Key` header, retries carrying the same key within `IDEMPOTENCY_TTL_SECS`
+/// return the record created by the first request instead of inserting it again. Keys are only
+/// supported for single records.
+///
+/// Malformed bodies are rejected with `422 Unprocessable Entity`, naming the offending field.
+#[utoipa::path(
+    post,
+    path = "/",
+    request_body = PostBody,
+    params(("Idempotency-Key" = Option<String>, Header, description = "Key making retries safe")),
+    responses(
+        (status = 201, description = "Inserted record, or array of records", body = openapi::CreatedBody),
+        (status = 200, description = "Reading repeating the last one of its source, which was dropped"),
+        (status = 400, description = "Invalid record", body = openapi::ErrorBody),
+        (status = 401, description = "Invalid API key", body = openapi::ErrorBody),
+        (status = 403, description = "Read-only instance", body = openapi::ErrorBody),
+        (status = 422, description = "Malformed JSON, naming the offending `field`", body = openapi::ErrorBody),
+        (status = 429, description = "Too many records from the source", body = openapi::ErrorBody),
+    ),
+    security(("api_key" = [])),
+)]
+async fn post_location(
+    State(app): State<App>,
+    headers: HeaderMap,
+    JsonBody(body): JsonBody<PostBody>,
+) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("POST / <- {:?}", body);
+
+    // Read the idempotency key
+    let key = match headers.get(IDEMPOTENCY_KEY).map(HeaderValue::to_str) {
+        None => None,
+        Some(Ok(key)) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN => Some(key),
+        Some(_) => {
+            let message = format!("Idempotency-Key must be 1 to {} visible ASCII characters", MAX_IDEMPOTENCY_KEY_LEN);
+            return ApiError::InvalidParameter(message).into_response();
+        }
+    };
+
+    // Insert arrays as a batch
+    let data = match body {
+        PostBody::One(data) => data,
+        PostBody::Many(_) if key.is_some() => {
+            let message = "Idempotency-Key is only supported for single records".to_string();
+            return ApiError::InvalidParameter(message).into_response();
+        }
+        PostBody::Many(items) => {
+            if let Err(e) = check_batch_size(&app, &items) {
+                return e.into_response();
+            }
+            return match insert_batch(&app, &items).await {
+                Ok(inserted) => (StatusCode::CREATED, Json(inserted)).into_response(),
+                Err(e) => e.into_response(),
+            };
+        }
+    };
+
+    // Return the record of an earlier request with the same key
+    if let Some(key) = key {
+        match find_idempotent(&app, key).await {
+            Ok(Some(data)) => {
+                tracing::info!("Record replayed for idempotency key {:?}: {:?}", key, data);
+                return (StatusCode::CREATED, location_header(&app, &data), Json(data)).into_response();
+            }
+            Ok(None) => {}
+            Err(e) => return e.into_response(),
+        }
+    }
+
+    // Insert the record and return the appropriate response
+    match insert_location(&app, &data, key).await {
+        Ok(Insertion::Inserted(data)) => {
+            (StatusCode::CREATED, location_header(&app, &data), Json(data)).into_response()
+        }
+        Ok(Insertion::Replayed(data)) => {
+            tracing::info!("Record replayed for idempotency key {:?}: {:?}", key, data);
+            (StatusCode::CREATED, location_header(&app, &data), Json(data)).into_response()
+        }
+        Ok(Insertion::Deduplicated(last)) => {
+            (StatusCode::OK, Json(json!({ "deduplicated": true, "location": last }))).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Handler for `POST /batch` endpoint.
+///
+/// It will insert many location records at once inside a single transaction, so either the whole
+/// batch is stored or none of it is. This is meant for devices replaying readings queued offline.
+async fn post_batch(State(app): State<App>, JsonBody(items): JsonBody<Vec<PostLocData>>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("POST /batch <- {} records", items.len());
+
+    // Keep the transaction bounded
+    if let Err(e) = check_batch_size(&app, &items) {
+        return e.into_response();
+    }
+
+    // Insert the records and return the appropriate response
+    match insert_batch(&app, &items).await {
+        Ok(inserted) => (StatusCode::CREATED, Json(json!({ "inserted": inserted.len() }))).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Handler for `POST /import` endpoint.
+///
+/// It will import the points of a GPX or CSV file uploaded as the `file` field of a multipart form,
+/// all recorded under the `source` field or else the `DEFAULT_SOURCE`. The format is given by the
+/// `format` field, or else by the content type of the file. Points that cannot be read or fail
+/// validation are skipped and reported by index, and the others are inserted inside a single
+/// transaction like `POST /batch`.
+async fn post_import(State(app): State<App>, mut multipart: Multipart) -> impl IntoResponse {
+    // Read the fields of the form
+    let mut source = None;
+    let mut format = None;
+    let mut file = None;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return ApiError::InvalidParameter(e.body_text()).into_response(),
+        };
+        let name = field.name().unwrap_or_default().to_string();
+        let content_type = field.content_type().map(str::to_string);
+        let value = match field.bytes().await {
+            Ok(value) => value,
+            Err(e) => return ApiError::InvalidParameter(e.body_text()).into_response(),
+        };
+        match name.as_str() {
+            "source" => source = Some(String::from_utf8_lossy(&value).trim().to_string()),
+            "format" => format = Some(String::from_utf8_lossy(&value).trim().to_string()),
+            "file" => file = Some((content_type, value)),
+            _ => {}
+        }
+    }
+
+    // Validate the fields
+    let Some(source) = source.filter(|s| !s.is_empty()).or_else(|| app.default_source.clone()) else {
+        return ApiError::InvalidParameter("Missing source".to_string()).into_response();
+    };
+    if let Err(e) = sanitise_string(&source) {
+        return ApiError::from(e).into_response();
+    }
+    let Some((content_type, file)) = file else {
+        return ApiError::InvalidParameter("Missing file".to_string()).into_response();
+    };
+    let format = match format.as_deref() {
+        Some(format) => ImportFormat::from_str(format),
+        None => content_type
+            .as_deref()
+            .and_then(ImportFormat::from_content_type)
+            .ok_or_else(|| "Cannot detect the format of the file, set format to gpx or csv".to_string()),
+    };
+    let format = match format {
+        Ok(format) => format,
+        Err(e) => return ApiError::InvalidParameter(e).into_response(),
+    };
+
+    // Log the request
+    tracing::info!("POST /import <- {:?} file of {} bytes for {}", format, file.len(), source);
+
+    // Parse the points, setting aside those that are invalid
+    let points = match import::parse(format, &file, &source) {
+        Ok(points) => points,
+        Err(e) => return ApiError::InvalidParameter(e).into_response(),
+    };
+    let mut items = Vec::with_capacity(points.len());
+    let mut skipped = Vec::new();
+    for (index, point) in points.into_iter().enumerate() {
+        match point.and_then(|data| validate_loc_data(&data).map(|_| data).map_err(|e| e.to_string())) {
+            Ok(data) => items.push(data),
+            Err(error) => skipped.push(json!({ "index": index, "error": error })),
+        }
+    }
+
+    // Insert the points and return the appropriate response
+    if items.is_empty() {
+        return (StatusCode::OK, Json(json!({ "imported": 0, "skipped": skipped }))).into_response();
+    }
+    match insert_batch(&app, &items).await {
+        Ok(inserted) => {
+            let body = json!({ "imported": inserted.len(), "skipped": skipped });
+            (StatusCode::CREATED, Json(body)).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Handler for `GET /` endpoint.
+///
+/// It will fetch all location records from the database as requested by the client. Optional parameters
+/// are used to filter the records. The format of the response is given by the `format` parameter,
+/// or else negotiated from the `Accept` header, defaulting to JSON. `X-Result-Truncated` tells when
+/// more records match beyond the page, except for NDJSON. With `enrich=true`, JSON records also get
+/// the name of their `place` from the geocoder at `GEOCODER_URL`, or `null` when it could not be
+/// found within `GEOCODER_TIMEOUT_MS`. With `format=polyline`, the track of each source is returned
+/// as a compact Google encoded polyline.
+///
+/// With `paginated=true`, JSON records are wrapped in `{"data": [...], "page": {...}}` telling the
+/// limit, offset, total number of matches and whether there are more. With `fields`, JSON records
+/// only keep the listed fields, to save bandwidth. `last=N` is a shortcut for the `N` most recent
+/// matching records, newest first, and cannot be combined with `limit`, `offset` or `order`.
+///
+/// JSON records carry a `quality` of `good`, `fair` or `poor`, bucketed from their accuracy with
+/// the `QUALITY_GOOD_M` and `QUALITY_FAIR_M` thresholds, or `unknown` without accuracy. `min_quality`
+/// keeps only records of at least the given quality.
+///
+/// Responses other than NDJSON carry an `ETag`, and `304 Not Modified` is returned when it matches
+/// `If-None-Match`, so that polling clients don't download unchanged records again.
+#[utoipa::path(
+    get,
+    path = "/",
+    params(GetLocQuery),
+    responses(
+        (status = 200, description = "Matching records, in the requested format", body = [DbLocData]),
+        (status = 304, description = "Records unchanged since the `ETag` in `If-None-Match`"),
+        (status = 400, description = "Invalid parameters", body = openapi::ErrorBody),
+    ),
+)]
+async fn get_all_locations(
+    State(app): State<App>,
+    headers: HeaderMap,
+    Query(query): Query<GetLocQuery>,
+) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET / <- {:?}", query);
+
+    // Validate the filters
+    if let Err(e) = query.validate_filters() {
+        return e.into_response();
+    }
+
+    // Validate the pagination parameters
+    let (limit, offset) = match query.page(app.default_page_size, app.max_page_size) {
+        Ok(page) => page,
+        Err(e) => return e.into_response(),
+    };
+    let page_size = [(X_PAGE_SIZE, limit.to_string())];
+
+    // Place names can only be added when a geocoder is configured
+    let enrich = query.enrich.unwrap_or(false);
+    if enrich && app.geocoder.is_none() {
+        return ApiError::InvalidParameter("Reverse geocoding is not configured".to_string()).into_response();
+    }
+
+    // Pick the format, the query parameter taking precedence over the `Accept` header
+    let format = query.format
+        .or_else(|| {
+            let accept = headers.get(header::ACCEPT)?.to_str().ok()?;
+            OutputFormat::from_accept(accept)
+        })
+        .unwrap_or_default();
+    let vary = [(header::VARY, "accept")];
+
+    // The envelope and the selection of fields are only defined for JSON
+    let paginated = query.paginated.unwrap_or(false);
+    if paginated && format != OutputFormat::Json {
+        return ApiError::InvalidParameter("paginated is only supported for JSON".to_string()).into_response();
+    }
+    if query.fields.is_some() && format != OutputFormat::Json {
+        return ApiError::InvalidParameter("fields is only supported for JSON".to_string()).into_response();
+    }
+
+    // Fetch the records from the database, with one more to tell whether there are more. NDJSON
+    // headers are sent before the records, so whether that result is truncated cannot be told.
+    let fetch_limit = if format == OutputFormat::Ndjson { limit } else { limit + 1 };
+    let mut builder = query.select(fetch_limit, offset, app.quality);
+
+    // Stream the records straight from the database when requested as NDJSON
+    if let OutputFormat::Ndjson = format {
+        return (
+            page_size,
+            vary,
+            [(header::CONTENT_TYPE, formats::NDJSON_CONTENT_TYPE)],
+            formats::ndjson_body(app.database_pool.clone(), builder, app.quality),
+        ).into_response();
+    }
+
+    let result = builder
+        .build_query_as::<DbLocData>()
+        .fetch_all(&app.database_pool)
+        .await;
+
+    // Verify the result and return the appropriate response
+    if let Ok(mut data) = result {
+        let truncated = truncate_results(&mut data, limit);
+        tracing::info!("{} records fetched: {:?}", data.len(), query);
+
+        // Count every matching record for the envelope
+        let page = if paginated {
+            let mut builder = QueryBuilder::new("SELECT COUNT(*) FROM locations");
+            query.push_filters(&mut builder, app.quality);
+            match builder.build_query_scalar::<i64>().fetch_one(&app.database_pool).await {
+                Ok(total) => Some(Page { limit, offset, total, has_more: truncated.is_some() }),
+                Err(e) => {
+                    tracing::error!("Cannot count records: {:?}", e);
+                    return ApiError::Database("No records fetched").into_response();
+                }
+            }
+        } else {
+            None
+        };
+
+        // Tell clients already holding these records that they did not change
+        let etag = entity_tag(&query, format, &data, page);
+        if etag_matches(&headers, &etag) {
+            return (StatusCode::NOT_MODIFIED, vary, [(header::ETAG, etag)]).into_response();
+        }
+        let etag = [(header::ETAG, etag)];
+
+        let response = match format {
+            OutputFormat::Json => match app.geocoder {
+                Some(ref geocoder) if enrich => {
+                    let points: Vec<(f64, f64)> = data.iter().map(|loc| (loc.latitude, loc.longitude)).collect();
+                    let places = geocoder.places(&points).await;
+                    let enriched: Vec<_> = data
+                        .into_iter()
+                        .zip(places)
+                        .map(|(location, place)| {
+                            let quality = app.quality.quality(location.accuracy);
+                            RatedLocData { location: EnrichedLocData { location, place }, quality }
+                        })
+                        .collect();
+                    json_fields(enriched, page, query.fields.as_ref())
+                }
+                _ => json_fields(rate(data, app.quality), page, query.fields.as_ref()),
+            },
+            OutputFormat::GeoJson => (
+                [(header::CONTENT_TYPE, formats::GEOJSON_CONTENT_TYPE)],
+                Json(formats::geojson(&data)),
+            ).into_response(),
+            OutputFormat::Csv => match formats::csv(&data) {
+                Ok(body) => (
+                    [
+                        (header::CONTENT_TYPE, formats::CSV_CONTENT_TYPE),
+                        (header::CONTENT_DISPOSITION, "attachment; filename=\"locations.csv\""),
+                    ],
+                    body,
+                ).into_response(),
+                Err(e) => {
+                    tracing::error!("Cannot write CSV: {:?}", e);
+                    ApiError::Internal("No records fetched").into_response()
+                }
+            },
+            OutputFormat::Polyline => Json(formats::polylines(&data)).into_response(),
+            OutputFormat::Ndjson => unreachable!("NDJSON is streamed above"),
+        };
+        (page_size, vary, etag, truncated, response).into_response()
+    } else {
+        tracing::error!("Cannot fetch records: {:?}", result);
+        ApiError::Database("No records fetched").into_response()
+    }
+}
+
+/// Handler for `POST /query` endpoint.
+///
+/// It will run many sets of the filters of `GET /` at once, concurrently, and return the records of
+/// each set in the same order, so that dashboards showing several panels need a single request. At
+/// most `MAX_QUERIES` sets are accepted, and their records are always returned as JSON.
+async fn post_query(State(app): State<App>, JsonBody(queries): JsonBody<Vec<GetLocQuery>>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("POST /query <- {:?}", queries);
+
+    // Validate every set of filters before touching the database
+    if queries.len() > MAX_QUERIES {
+        let message = format!("At most {} queries are allowed", MAX_QUERIES);
+        return ApiError::InvalidParameter(message).into_response();
+    }
+    let mut builders = Vec::with_capacity(queries.len());
+    for (index, query) in queries.iter().enumerate() {
+        let result = query.validate_filters()
+            .and_then(|()| {
+                let json = query.format.unwrap_or_default() == OutputFormat::Json;
+                if !json || query.enrich == Some(true) || query.paginated == Some(true) || query.fields.is_some() {
+                    let message = "format, enrich=true, paginated=true and fields are not supported by POST /query";
+                    return Err(ApiError::InvalidParameter(message.to_string()));
+                }
+                query.page(app.default_page_size, app.max_page_size)
+            });
+        match result {
+            Ok((limit, offset)) => builders.push(query.select(limit, offset, app.quality)),
+            Err(e) => return ApiError::InvalidQuery { index, error: Box::new(e) }.into_response(),
+        }
+    }
+
+    // Fetch the records of every set from the database concurrently
+    let mut tasks = JoinSet::new();
+    for (index, mut builder) in builders.into_iter().enumerate() {
+        let pool = app.database_pool.clone();
+        tasks.spawn(async move {
+            let result = builder.build_query_as::<DbLocData>().fetch_all(&pool).await;
+            (index, result)
+        });
+    }
+    let mut results: Vec<Vec<RatedLocData<DbLocData>>> = (0..queries.len()).map(|_| Vec::new()).collect();
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((index, Ok(data))) => results[index] = rate(data, app.quality),
+            Ok((_, Err(e))) => {
+                tracing::error!("Cannot fetch records: {:?}", e);
+                return ApiError::Database("No records fetched").into_response();
+            }
+            Err(e) => {
+                tracing::error!("Query task failed: {:?}", e);
+                return ApiError::Internal("No records fetched").into_response();
+            }
+        }
+    }
+
+    // Return the records of every set, in the order of the sets
+    tracing::info!("{} result sets fetched", results.len());
+    Json(results).into_response()
+}
+
+/// Handler for `GET /count` endpoint.
+///
+/// It will count the location records matching the same filters as `GET /`, so clients can check the
+/// size of a result set before fetching it. Counts are cached for `CACHE_TTL_SECS`.
+async fn get_count(State(app): State<App>, Query(query): Query<GetLocQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /count <- {:?}", query);
+
+    // Validate the filters
+    if let Err(e) = query.validate_filters() {
+        return e.into_response();
+    }
+
+    // Count the records in the cache, or else in the database
+    let key = format!("{:?}", query);
+    let result = match app.count_cache.get(&key) {
+        Some(count) => Ok(count),
+        None => {
+            let mut builder = QueryBuilder::new("SELECT COUNT(*) FROM locations");
+            query.push_filters(&mut builder, app.quality);
+            builder
+                .build_query_scalar::<i64>()
+                .fetch_one(&app.database_pool)
+                .await
+                .inspect(|&count| app.count_cache.insert(key, count))
+        }
+    };
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(count) => {
+            tracing::info!("{} records counted: {:?}", count, query);
+            Json(json!({ "count": count })).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot count records: {:?}", e);
+            ApiError::Database("No records counted").into_response()
+        }
+    }
+}
+
+/// Data structure for the extent of the records returned by `GET /bounds`, whose fields are all
+/// `null` when no record matches.
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Bounds {
+    min_lat: Option<f64>,
+    max_lat: Option<f64>,
+    min_lon: Option<f64>,
+    max_lon: Option<f64>,
+    center_lat: Option<f64>,
+    center_lon: Option<f64>,
+}
+
+/// Handler for `GET /bounds` endpoint.
+///
+/// It will return the bounding box of the location records matching the same filters as `GET /`,
+/// along with its centre, so that maps can set their initial view without fetching every record.
+/// Tracks crossing the antimeridian get a box spanning the whole world in between.
+async fn get_bounds(State(app): State<App>, Query(query): Query<GetLocQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /bounds <- {:?}", query);
+
+    // Validate the filters
+    if let Err(e) = query.validate_filters() {
+        return e.into_response();
+    }
+
+    // Compute the extent in the database
+    let mut builder = QueryBuilder::new(
+        "SELECT MIN(latitude), MAX(latitude), MIN(longitude), MAX(longitude) FROM locations",
+    );
+    query.push_filters(&mut builder, app.quality);
+    let result = builder
+        .build_query_as::<(Option<f64>, Option<f64>, Option<f64>, Option<f64>)>()
+        .fetch_one(&app.database_pool)
+        .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok((min_lat, max_lat, min_lon, max_lon)) => {
+            let center = |min: Option<f64>, max: Option<f64>| Some((min? + max?) / 2.0);
+            let bounds = Bounds {
+                min_lat,
+                max_lat,
+                min_lon,
+                max_lon,
+                center_lat: center(min_lat, max_lat),
+                center_lon: center(min_lon, max_lon),
+            };
+            tracing::info!("Bounds computed: {:?}", bounds);
+            Json(bounds).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot compute bounds: {:?}", e);
+            ApiError::Database("No bounds computed").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /locations/:id` endpoint.
+///
+/// It will fetch a single location record by its primary key. Deleted records are only found with
+/// `include_deleted=true`.
+async fn get_location_by_id(
+    State(app): State<App>,
+    Path(id): Path<i64>,
+    Query(query): Query<DeletedQuery>,
+) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /locations/{} <- {:?}", id, query);
+
+    // Fetch the record from the database
+    let include_deleted = query.include_deleted.unwrap_or(false);
+    let result = sqlx::query_as!(
+        DbLocData,
+        "SELECT * FROM locations WHERE id = ?1 AND (?2 OR deleted_at IS NULL)",
+        id,
+        include_deleted,
+    )
+    .fetch_optional(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(Some(data)) => {
+            tracing::info!("Record fetched: {:?}", data);
+            Json(data).into_response()
+        }
+        Ok(None) => ApiError::NotFound.into_response(),
+        Err(e) => {
+            tracing::error!("Cannot fetch record: {:?}", e);
+            ApiError::Database("No record fetched").into_response()
+        }
+    }
+}
+
+/// Handler for `PUT /locations/:id` endpoint.
+///
+/// It will replace the source, coordinates and measurements of an existing location record, e.g. to
+/// fix a wrong source label. The `id` and `created_at` of a record can never be changed.
+async fn put_location(
+    State(app): State<App>,
+    Path(id): Path<i64>,
+    JsonBody(data): JsonBody<PostLocData>,
+) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("PUT /locations/{} <- {:?}", id, data);
+
+    // Validate the location data
+    if data.created_at.is_some() {
+        return ApiError::InvalidTimestamp("created_at cannot be changed".to_string()).into_response();
+    }
+    let data = with_default_source(&data, app.default_source.as_deref());
+    if let Err(e) = validate_loc_data(&data) {
+        return e.into_response();
+    }
+    let data = round_coords(&data, app.coord_precision);
+
+    // Update the record in the database
+    let result = sqlx::query_as!(
+        DbLocData,
+        r#"
+        UPDATE locations
+        SET source = ?, latitude = ?, longitude = ?, altitude = ?, accuracy = ?, speed = ?, battery = ?, provider = ?, tags = ?
+        WHERE id = ? AND deleted_at IS NULL
+        RETURNING *
+        "#,
+        data.source,
+        data.latitude,
+        data.longitude,
+        data.altitude,
+        data.accuracy,
+        data.speed,
+        data.battery,
+        data.provider,
+        data.tags,
+        id,
+    )
+    .fetch_optional(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(Some(data)) => {
+            tracing::info!("Record updated: {:?}", data);
+            Json(data).into_response()
+        }
+        Ok(None) => ApiError::NotFound.into_response(),
+        Err(e) => {
+            tracing::error!("Cannot update record: {:?}", e);
+            ApiError::Database("No record updated").into_response()
+        }
+    }
+}
+
+/// Handler for `DELETE /locations/:id` endpoint.
+///
+/// It will delete a single location record by its primary key. The record is only marked as deleted,
+/// so it can be recovered until it is purged with `DELETE /purge`.
+async fn delete_location(State(app): State<App>, Path(id): Path<i64>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("DELETE /locations/{} <-", id);
+
+    // Mark the record as deleted in the database
+    let result = sqlx::query!(
+        "UPDATE locations SET deleted_at = CURRENT_TIMESTAMP WHERE id = ? AND deleted_at IS NULL",
+        id,
+    )
+    .execute(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(done) if done.rows_affected() > 0 => {
+            tracing::info!("Record deleted: {}", id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(_) => ApiError::NotFound.into_response(),
+        Err(e) => {
+            tracing::error!("Cannot delete record: {:?}", e);
+            ApiError::Database("No record deleted").into_response()
+        }
+    }
+}
+
+/// Permanently delete the records created before `before`, whether marked as deleted or not.
+///
+/// Returns the number of records deleted.
+async fn purge_locations(pool: &SqlitePool, before: NaiveDateTime) -> Result<u64, sqlx::Error> {
+    let done = sqlx::query!("DELETE FROM locations WHERE created_at < ?", before)
+        .execute(pool)
+        .await?;
+    Ok(done.rows_affected())
+}
+
+/// Periodically delete the records older than `retention`, until `shutdown` is signalled.
+///
+/// It should be spawned as a background task.
+async fn purge_periodically(
+    pool: SqlitePool,
+    retention: TimeDelta,
+    period: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = shutdown.changed() => break,
+        }
+        let before = Utc::now().naive_utc() - retention;
+        match purge_locations(&pool, before).await {
+            Ok(deleted) => tracing::info!("Retention removed {} records created before {}", deleted, before),
+            Err(e) => tracing::error!("Cannot purge records: {:?}", e),
+        }
+    }
+}
+
+/// Handler for `DELETE /locations` endpoint.
+///
+/// It will delete the location records created before `before`, optionally only those of
+/// `source`, and return how many were deleted. `before` is required so that a bare request cannot
+/// wipe the whole table. The records are only marked as deleted, until purged with `DELETE /purge`.
+async fn delete_locations(State(app): State<App>, Query(query): Query<PurgeQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("DELETE /locations <- {:?}", query);
+
+    // Validate the parameters
+    let Some(before) = query.before else {
+        return ApiError::InvalidParameter("before is required".to_string()).into_response();
+    };
+    if let Some(ref s) = query.source {
+        if let Err(e) = sanitise_string(s) {
+            return ApiError::from(e).into_response();
+        }
+    }
+
+    // Mark the records as deleted in the database
+    let result = sqlx::query!(
+        r#"
+        UPDATE locations SET deleted_at = CURRENT_TIMESTAMP
+        WHERE created_at < ?1 AND (?2 IS NULL OR source = ?2) AND deleted_at IS NULL
+        "#,
+        before,
+        query.source,
+    )
+    .execute(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(done) => {
+            let deleted = done.rows_affected();
+            tracing::info!("{} records deleted: {:?}", deleted, query);
+            Json(json!({ "deleted": deleted })).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot purge records: {:?}", e);
+            ApiError::Database("No records deleted").into_response()
+        }
+    }
+}
+
+/// Handler for `DELETE /purge` endpoint.
+///
+/// It will permanently remove the records marked as deleted, optionally only those deleted before
+/// `before`, and return how many were purged. Purged records cannot be recovered.
+async fn purge_deleted(State(app): State<App>, Query(query): Query<PurgeDeletedQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("DELETE /purge <- {:?}", query);
+
+    // Remove the records from the database
+    let result = sqlx::query!(
+        "DELETE FROM locations WHERE deleted_at IS NOT NULL AND (?1 IS NULL OR deleted_at < ?1)",
+        query.before,
+    )
+    .execute(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(done) => {
+            let purged = done.rows_affected();
+            tracing::warn!("{} deleted records purged: {:?}", purged, query);
+            Json(json!({ "purged": purged })).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot purge records: {:?}", e);
+            ApiError::Database("No records purged").into_response()
+        }
+    }
+}
+
+/// Handler for `DELETE /sources/:source` endpoint.
+///
+/// It will delete every location record of `source`, such as a decommissioned device, and return
+/// how many were deleted. As the whole history of the source goes, `confirm=true` is required. The
+/// records are only marked as deleted, until purged with `DELETE /purge`.
+async fn delete_source(
+    State(app): State<App>,
+    Path(source): Path<String>,
+    Query(query): Query<ConfirmQuery>,
+) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("DELETE /sources/{} <- {:?}", source, query);
+
+    // Validate the parameters
+    if let Err(e) = sanitise_string(&source) {
+        return ApiError::from(e).into_response();
+    }
+    if query.confirm != Some(true) {
+        return ApiError::InvalidParameter("confirm=true is required to delete a source".to_string()).into_response();
+    }
+
+    // Mark the records as deleted in the database
+    let result = sqlx::query!(
+        "UPDATE locations SET deleted_at = CURRENT_TIMESTAMP WHERE source = ? AND deleted_at IS NULL",
+        source,
+    )
+    .execute(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(done) => {
+            let deleted = done.rows_affected();
+            tracing::warn!("Source {:?} deleted with its {} records", source, deleted);
+            Json(json!({ "deleted": deleted })).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot delete source: {:?}", e);
+            ApiError::Database("No records deleted").into_response()
+        }
+    }
+}
+
+/// Handler for `POST /sources/:source/rename` endpoint.
+///
+/// It will move every location record of `source` to the `new` name, such as a relabelled device,
+/// and return how many were moved. Records marked as deleted move too, so restoring them keeps the
+/// history together. Renaming into a source that already has records merges both histories, so it
+/// is refused unless `merge` is `true`.
+async fn rename_source(
+    State(app): State<App>,
+    Path(source): Path<String>,
+    JsonBody(body): JsonBody<RenameSource>,
+) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("POST /sources/{}/rename <- {:?}", source, body);
+
+    // Validate the names
+    if let Err(e) = sanitise_string(&source).and_then(|()| sanitise_string(&body.new)) {
+        return ApiError::from(e).into_response();
+    }
+    if source == body.new {
+        return ApiError::InvalidParameter("new must differ from the current name".to_string()).into_response();
+    }
+
+    // Move the records in the database, checking for a collision in the same transaction
+    let result = retry_busy(|| async {
+        let mut tx = app.database_pool.begin().await?;
+        if !body.merge {
+            let exists = sqlx::query_scalar!(
+                r#"SELECT EXISTS (SELECT 1 FROM locations WHERE source = ?) AS "exists!: bool""#,
+                body.new,
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            if exists {
+                return Ok(Renaming::Exists);
+            }
+        }
+        let done = sqlx::query!("UPDATE locations SET source = ? WHERE source = ?", body.new, source)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Renaming::Renamed(done.rows_affected()))
+    }).await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(Renaming::Renamed(renamed)) => {
+            tracing::warn!("Source {:?} renamed to {:?} with its {} records", source, body.new, renamed);
+            Json(json!({ "renamed": renamed })).into_response()
+        }
+        Ok(Renaming::Exists) => ApiError::SourceExists(body.new).into_response(),
+        Err(e) => {
+            tracing::error!("Cannot rename source: {:?}", e);
+            ApiError::Database("No records renamed").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /nearby` endpoint.
+///
+/// It will fetch all location records within `radius_km` of the given point, sorted by ascending
+/// distance.
+async fn get_nearby_locations(State(app): State<App>, Query(query): Query<NearbyQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /nearby <- {:?}", query);
+
+    // Validate the centre point and radius
+    if let Err(e) = validate_coords(query.lat, query.lon) {
+        return ApiError::InvalidCoordinates(e).into_response();
+    }
+    if !query.radius_km.is_finite() || query.radius_km < 0.0 {
+        return ApiError::InvalidParameter("Invalid radius_km".to_string()).into_response();
+    }
+
+    // Fetch the records within the radius, nearest first
+    let result = find_nearby(&app, (query.lat, query.lon), query.radius_km).await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(nearby) => {
+            tracing::info!("{} records fetched: {:?}", nearby.len(), query);
+            Json(nearby).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot fetch records: {:?}", e);
+            ApiError::Database("No records fetched").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /locations/:id/nearby` endpoint.
+///
+/// It will fetch the other location records within `radius_km` of the record `id`, sorted by
+/// ascending distance, like `GET /nearby` around its coordinates.
+async fn get_nearby_record(
+    State(app): State<App>,
+    Path(id): Path<i64>,
+    Query(query): Query<RadiusQuery>,
+) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /locations/{}/nearby <- {:?}", id, query);
+
+    // Validate the radius
+    if !query.radius_km.is_finite() || query.radius_km < 0.0 {
+        return ApiError::InvalidParameter("Invalid radius_km".to_string()).into_response();
+    }
+
+    // Fetch the anchor record, then the records around it
+    let result = sqlx::query_as!(
+        DbLocData,
+        "SELECT * FROM locations WHERE id = ? AND deleted_at IS NULL",
+        id,
+    )
+    .fetch_optional(&app.database_pool)
+    .await;
+    let result = match result {
+        Ok(Some(anchor)) => find_nearby(&app, (anchor.latitude, anchor.longitude), query.radius_km).await,
+        Ok(None) => return ApiError::NotFound.into_response(),
+        Err(e) => Err(e),
+    };
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(mut nearby) => {
+            nearby.retain(|n| n.location.id != id);
+            tracing::info!("{} records fetched near {}: {:?}", nearby.len(), id, query);
+            Json(nearby).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot fetch records: {:?}", e);
+            ApiError::Database("No records fetched").into_response()
+        }
+    }
+}
+
+/// Fetch the location records within `radius_km` of `center`, sorted by ascending distance.
+///
+/// Candidates are pre-filtered by a bounding box in SQL, then checked exactly in Rust.
+async fn find_nearby(app: &App, center: (f64, f64), radius_km: f64) -> Result<Vec<NearbyLocData>, sqlx::Error> {
+    let (min_lat, max_lat, min_lon, max_lon) = geo::bounding_box(center, radius_km);
+    let data = sqlx::query_as!(
+        DbLocData,
+        r#"
+        SELECT * FROM locations
+        WHERE latitude BETWEEN ?1 AND ?2 AND longitude BETWEEN ?3 AND ?4 AND deleted_at IS NULL
+        "#,
+        min_lat,
+        max_lat,
+        min_lon,
+        max_lon,
+    )
+    .fetch_all(&app.database_pool)
+    .await?;
+
+    let mut nearby: Vec<NearbyLocData> = data
+        .into_iter()
+        .map(|location| {
+            let distance_km = geo::haversine_km(center, (location.latitude, location.longitude));
+            NearbyLocData { location, distance_km }
+        })
+        .filter(|n| n.distance_km <= radius_km)
+        .collect();
+    nearby.sort_by(|a, b| a.distance_km.total_cmp(&b.distance_km));
+    Ok(nearby)
+}
+
+/// Handler for `GET /health` endpoint.
+///
+/// It will check that the database is reachable, so it can be used as a liveness/readiness probe.
+async fn get_health(State(app): State<App>) -> impl IntoResponse {
+    match sqlx::query("SELECT 1").execute(&app.database_pool).await {
+        Ok(_) => (StatusCode::OK, Json(json!({ "status": "ok" }))).into_response(),
+        Err(e) => {
+            tracing::error!("Health check failed: {:?}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "status": "db_unreachable" }))).into_response()
+        }
+    }
+}
+
+/// Handler for `GET /version` endpoint.
+///
+/// It will return the version of the crate, the commit it was built from and when it was built, to
+/// tell which build is deployed.
+async fn get_version() -> impl IntoResponse {
+    let built_at = env!("BUILD_TIMESTAMP").parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map(|t| timestamp::format(&t.naive_utc()));
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("GIT_HASH"),
+        "built_at": built_at,
+    }))
+}
+
+/// Handler for `GET /debug/config` endpoint.
+///
+/// It will return the effective configuration of the process, without its secrets, to diagnose
+/// deployments. It is only served when `DEBUG_ENDPOINTS` is enabled, and requires the API key like
+/// write endpoints.
+async fn get_debug_config(State(app): State<App>) -> impl IntoResponse {
+    match app.debug_config {
+        Some(config) => Json(config).into_response(),
+        None => ApiError::RouteNotFound.into_response(),
+    }
+}
+
+/// Handler for `GET /export.gpx` endpoint.
+///
+/// It will export the location records matching the filters as a GPX track, ordered by
+/// `created_at`, for use in GPS tools such as Strava. At most `MAX_RESULTS` records are exported,
+/// and `X-Result-Truncated` tells when there were more.
+async fn get_export_gpx(State(app): State<App>, Query(query): Query<TrackQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /export.gpx <- {:?}", query);
+
+    // Validate the filters
+    if let Err(e) = query.validate_filters() {
+        return e.into_response();
+    }
+
+    // Fetch the records from the database, with one more to tell whether there are more
+    let fetch_limit = app.max_results + 1;
+    let result = sqlx::query_as!(
+        DbLocData,
+        r#"
+        SELECT * FROM locations
+        WHERE 
+            (?1 IS NULL OR source = ?1)
+            AND (?2 IS NULL OR created_at >= ?2)
+            AND (?3 IS NULL OR created_at <= ?3)
+            AND deleted_at IS NULL
+        ORDER BY created_at ASC
+        LIMIT ?4
+        "#,
+        query.source,
+        query.from,
+        query.to,
+        fetch_limit,
+    )
+    .fetch_all(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(mut data) => {
+            let truncated = truncate_results(&mut data, app.max_results);
+            tracing::info!("{} records exported: {:?}", data.len(), query);
+            let name = query.source.as_deref().unwrap_or("ntb_location");
+            (
+                truncated,
+                [(header::CONTENT_TYPE, formats::GPX_CONTENT_TYPE)],
+                formats::gpx(&data, name),
+            ).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot fetch records: {:?}", e);
+            ApiError::Database("No records fetched").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /export.kml` endpoint.
+///
+/// It will export the location records matching the same filters as `GET /` as a KML document,
+/// ordered by `created_at`, for viewing in Google Earth. Pagination does not apply, but at most
+/// `MAX_RESULTS` records are exported, and `X-Result-Truncated` tells when there were more.
+async fn get_export_kml(State(app): State<App>, Query(query): Query<GetLocQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /export.kml <- {:?}", query);
+
+    // Validate the filters
+    if let Err(e) = query.validate_filters() {
+        return e.into_response();
+    }
+
+    // Fetch the records from the database, with one more to tell whether there are more
+    let mut builder = QueryBuilder::new("SELECT * FROM locations");
+    query.push_filters(&mut builder, app.quality);
+    builder.push(" ORDER BY created_at ASC LIMIT ").push_bind(app.max_results + 1);
+    let result = builder
+        .build_query_as::<DbLocData>()
+        .fetch_all(&app.database_pool)
+        .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(mut data) => {
+            let truncated = truncate_results(&mut data, app.max_results);
+            tracing::info!("{} records exported: {:?}", data.len(), query);
+            let name = match query.source {
+                Some(SourceList(ref sources)) if sources.len() == 1 => sources[0].as_str(),
+                _ => "ntb_location",
+            };
+            (
+                truncated,
+                [(header::CONTENT_TYPE, formats::KML_CONTENT_TYPE)],
+                formats::kml(&data, name),
+            ).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot fetch records: {:?}", e);
+            ApiError::Database("No records fetched").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /sources` endpoint.
+///
+/// It will list the distinct sources known to the database in alphabetical order. With
+/// `counts=true`, each source is returned along with its number of records. The list is cached for
+/// `CACHE_TTL_SECS`.
+async fn get_sources(State(app): State<App>, Query(query): Query<SourcesQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /sources <- {:?}", query);
+
+    // Fetch the sources from the cache, or else from the database
+    let result = match app.sources_cache.get("") {
+        Some(data) => Ok(data),
+        None => sqlx::query_as!(
+            SourceCount,
+            r#"
+            SELECT source, COUNT(*) AS "count!: i64" FROM locations
+            WHERE deleted_at IS NULL
+            GROUP BY source
+            ORDER BY source
+            "#,
+        )
+        .fetch_all(&app.database_pool)
+        .await
+        .inspect(|data| app.sources_cache.insert(String::new(), data.clone())),
+    };
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(data) => {
+            tracing::info!("{} sources fetched", data.len());
+            if query.counts.unwrap_or(false) {
+                Json(data).into_response()
+            } else {
+                let sources: Vec<String> = data.into_iter().map(|s| s.source).collect();
+                Json(sources).into_response()
+            }
+        }
+        Err(e) => {
+            tracing::error!("Cannot fetch sources: {:?}", e);
+            ApiError::Database("No sources fetched").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /sources/status` endpoint.
+///
+/// It will return when each source last reported and how many seconds ago, flagging the sources
+/// silent for longer than `STALE_AFTER_SECS` as stale, so a fleet can be monitored in one call.
+async fn get_sources_status(State(app): State<App>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /sources/status <-");
+
+    // Fetch the last report of each source from the database
+    let result = sqlx::query!(
+        r#"
+        SELECT source AS "source!", MAX(created_at) AS "last_seen!: NaiveDateTime" FROM locations
+        WHERE deleted_at IS NULL
+        GROUP BY source
+        ORDER BY source
+        "#,
+    )
+    .fetch_all(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(rows) => {
+            let now = Utc::now().naive_utc();
+            let statuses: Vec<SourceStatus> = rows
+                .into_iter()
+                .map(|row| {
+                    let age = now - row.last_seen;
+                    SourceStatus {
+                        source: row.source,
+                        last_seen: row.last_seen,
+                        age_secs: age.num_seconds(),
+                        stale: age > app.stale_after,
+                    }
+                })
+                .collect();
+            tracing::info!("{} source statuses fetched", statuses.len());
+            Json(statuses).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot fetch source statuses: {:?}", e);
+            ApiError::Database("No sources fetched").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /latest` endpoint.
+///
+/// It will return the most recently inserted record of each source, ordered by source, or only
+/// that of `source` when given. This spares dashboards from downloading whole histories.
+async fn get_latest_locations(State(app): State<App>, Query(query): Query<LatestQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /latest <- {:?}", query);
+
+    // Validate the source
+    if let Some(ref s) = query.source {
+        if let Err(e) = sanitise_string(s) {
+            return ApiError::from(e).into_response();
+        }
+    }
+
+    // Fetch the records from the database
+    let result = sqlx::query_as!(
+        DbLocData,
+        r#"
+        SELECT * FROM locations
+        WHERE id IN (
+            SELECT MAX(id) FROM locations
+            WHERE (?1 IS NULL OR source = ?1) AND deleted_at IS NULL
+            GROUP BY source
+        )
+        ORDER BY source
+        "#,
+        query.source,
+    )
+    .fetch_all(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(data) => {
+            tracing::info!("{} latest records fetched", data.len());
+            Json(data).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot fetch latest records: {:?}", e);
+            ApiError::Database("No records fetched").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /stream` endpoint.
+///
+/// It will push each record inserted through `POST /` as a Server-Sent Event whose data is the
+/// record in JSON, optionally only for `source`. Clients too slow to keep up miss some records
+/// rather than hold up the others.
+async fn get_stream(State(app): State<App>, Query(query): Query<StreamQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /stream <- {:?}", query);
+
+    // Validate the source
+    if let Some(ref s) = query.source {
+        if let Err(e) = sanitise_string(s) {
+            return ApiError::from(e).into_response();
+        }
+    }
+
+    // Forward the matching records as they are inserted
+    let events = BroadcastStream::new(app.updates.subscribe()).filter_map(move |update| match update {
+        Ok(loc) if query.source.as_ref().is_none_or(|s| *s == loc.source) => {
+            Some(Event::default().json_data(loc))
+        }
+        Ok(_) => None,
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+            tracing::warn!("Stream subscriber lagging behind, {} records skipped", skipped);
+            None
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// Handler for `GET /ws` endpoint.
+///
+/// It will upgrade the connection to a WebSocket, which receives every inserted record like
+/// `GET /stream` does, and accepts records sent by the client as JSON text frames. Accepted records
+/// come back through the same feed, while rejected ones are answered with the usual error object.
+///
+/// Subscribing is a read, so anyone may connect. Records are only accepted like on the other write
+/// endpoints: when the instance is not read-only, and from connections opened with the API key.
+async fn get_ws(State(app): State<App>, headers: HeaderMap, ws: WebSocketUpgrade) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /ws");
+
+    let authorised = has_api_key(&app, &headers);
+    ws.on_upgrade(move |socket| handle_ws(app, socket, authorised))
+}
+
+/// Serve a WebSocket connection of `GET /ws` until either side closes it.
+///
+/// Records sent over connections that were not `authorised` with the API key are rejected.
+async fn handle_ws(app: App, mut socket: WebSocket, authorised: bool) {
+    let mut updates = app.updates.subscribe();
+    let start = tokio::time::Instant::now() + WS_PING_INTERVAL;
+    let mut ping = tokio::time::interval_at(start, WS_PING_INTERVAL);
+
+    loop {
+        let outgoing = tokio::select! {
+            // Forward inserted records
+            update = updates.recv() => match update {
+                Ok(loc) => match serde_json::to_string(&loc) {
+                    Ok(text) => Message::Text(text),
+                    Err(e) => {
+                        tracing::error!("Cannot serialise record: {:?}", e);
+                        continue;
+                    }
+                },
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("WebSocket subscriber lagging behind, {} records skipped", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+
+            // Insert records sent by the client
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    tracing::info!("WS <- {}", text);
+                    let result = match serde_json::from_str::<PostLocData>(&text) {
+                        _ if app.read_only => Err(ApiError::ReadOnly),
+                        _ if !authorised => Err(ApiError::Unauthorized),
+                        Ok(data) => insert_location(&app, &data, None).await.map(|_| ()),
+                        Err(e) => Err(ApiError::InvalidParameter(format!("Invalid record: {}", e))),
+                    };
+                    match result {
+                        Ok(()) => continue,
+                        Err(e) => {
+                            let body = json!({ "error": e.to_string(), "code": e.code() });
+                            Message::Text(body.to_string())
+                        }
+                    }
+                }
+                // Pings are answered automatically, and binary frames are not supported
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Binary(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Err(e)) => {
+                    tracing::warn!("WebSocket error: {:?}", e);
+                    break;
+                }
+            },
+
+            // Keep the connection alive and detect dead peers
+            _ = ping.tick() => Message::Ping(Vec::new()),
+        };
+
+        if socket.send(outgoing).await.is_err() {
+            break;
+        }
+    }
+
+    tracing::info!("WebSocket closed");
+}
+
+/// Handler for `GET /stats/daily` endpoint.
+///
+/// It will count the location records per day and per source, optionally filtered by source and
+/// time range, so clients can chart activity without fetching the raw records.
+async fn get_daily_stats(State(app): State<App>, Query(query): Query<TrackQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /stats/daily <- {:?}", query);
+
+    // Validate the filters
+    if let Err(e) = query.validate_filters() {
+        return e.into_response();
+    }
+
+    // Aggregate the records in the database
+    let result = sqlx::query_as!(
+        DailyCount,
+        r#"
+        SELECT date(created_at) AS "day!: NaiveDate", source, COUNT(*) AS "count!: i64"
+        FROM locations
+        WHERE 
+            (?1 IS NULL OR source = ?1)
+            AND (?2 IS NULL OR created_at >= ?2)
+            AND (?3 IS NULL OR created_at <= ?3)
+            AND deleted_at IS NULL
+        GROUP BY date(created_at), source
+        ORDER BY date(created_at), source
+        "#,
+        query.source,
+        query.from,
+        query.to,
+    )
+    .fetch_all(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(data) => {
+            tracing::info!("{} daily counts fetched: {:?}", data.len(), query);
+            Json(data).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot fetch daily counts: {:?}", e);
+            ApiError::Database("No daily counts fetched").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /render.png` endpoint.
+///
+/// It will draw the location records matching the filters as a PNG image of `width` by `height`
+/// pixels, zoomed to fit their bounds, for embedding in chat or email. At most `MAX_RESULTS`
+/// records are drawn, the earliest ones, and `X-Result-Truncated` tells when there were more.
+async fn get_render_png(State(app): State<App>, Query(query): Query<RenderQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /render.png <- {:?}", query);
+
+    // Validate the parameters
+    if let Some(ref s) = query.source {
+        if let Err(e) = sanitise_string(s) {
+            return ApiError::from(e).into_response();
+        }
+    }
+    if let Err(e) = validate_time_range(query.from, query.to) {
+        return e.into_response();
+    }
+    let width = query.width.unwrap_or(DEFAULT_RENDER_SIZE.0);
+    let height = query.height.unwrap_or(DEFAULT_RENDER_SIZE.1);
+    if !(1..=MAX_RENDER_SIZE).contains(&width) || !(1..=MAX_RENDER_SIZE).contains(&height) {
+        let message = format!("width and height must be between 1 and {}", MAX_RENDER_SIZE);
+        return ApiError::InvalidParameter(message).into_response();
+    }
+
+    // Fetch the records from the database, with one more to tell whether there are more
+    let fetch_limit = app.max_results + 1;
+    let result = sqlx::query_as!(
+        DbLocData,
+        r#"
+        SELECT * FROM locations
+        WHERE 
+            (?1 IS NULL OR source = ?1)
+            AND (?2 IS NULL OR created_at >= ?2)
+            AND (?3 IS NULL OR created_at <= ?3)
+            AND deleted_at IS NULL
+        ORDER BY created_at ASC
+        LIMIT ?4
+        "#,
+        query.source,
+        query.from,
+        query.to,
+        fetch_limit,
+    )
+    .fetch_all(&app.database_pool)
+    .await;
+    let mut data = match result {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::error!("Cannot fetch records: {:?}", e);
+            return ApiError::Database("No records fetched").into_response();
+        }
+    };
+    let truncated = truncate_results(&mut data, app.max_results);
+
+    // Draw the records and return the appropriate response
+    match render::png(&data, width, height) {
+        Ok(image) => {
+            tracing::info!("{} records rendered: {:?}", data.len(), query);
+            (truncated, [(header::CONTENT_TYPE, render::PNG_CONTENT_TYPE)], image).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot render records: {:?}", e);
+            ApiError::Internal("No image rendered").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /heatmap` endpoint.
+///
+/// It will count the location records per cell of a grid, optionally filtered by source and time
+/// range, so clients can render a density heatmap without fetching the raw records. Cells are
+/// found by rounding coordinates to `precision` decimal places, and only the `MAX_RESULTS` busiest
+/// cells are returned.
+async fn get_heatmap(State(app): State<App>, Query(query): Query<HeatmapQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /heatmap <- {:?}", query);
+
+    // Validate the parameters
+    if let Some(ref s) = query.source {
+        if let Err(e) = sanitise_string(s) {
+            return ApiError::from(e).into_response();
+        }
+    }
+    if let Err(e) = validate_time_range(query.from, query.to) {
+        return e.into_response();
+    }
+    let precision = query.precision.unwrap_or(DEFAULT_HEATMAP_PRECISION);
+    if precision > MAX_HEATMAP_PRECISION {
+        let message = format!("precision must be at most {}", MAX_HEATMAP_PRECISION);
+        return ApiError::InvalidParameter(message).into_response();
+    }
+
+    // Aggregate the records in the database
+    let result = sqlx::query_as!(
+        HeatmapCell,
+        r#"
+        SELECT ROUND(latitude, ?4) AS "lat!: f64", ROUND(longitude, ?4) AS "lon!: f64", COUNT(*) AS "count!: i64"
+        FROM locations
+        WHERE 
+            (?1 IS NULL OR source = ?1)
+            AND (?2 IS NULL OR created_at >= ?2)
+            AND (?3 IS NULL OR created_at <= ?3)
+            AND deleted_at IS NULL
+        GROUP BY 1, 2
+        ORDER BY 3 DESC, 1, 2
+        LIMIT ?5
+        "#,
+        query.source,
+        query.from,
+        query.to,
+        precision,
+        app.max_results,
+    )
+    .fetch_all(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(data) => {
+            tracing::info!("{} heatmap cells fetched: {:?}", data.len(), query);
+            Json(data).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot fetch heatmap cells: {:?}", e);
+            ApiError::Database("No heatmap cells fetched").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /stats/distance` endpoint.
+///
+/// It will sum the Haversine distances between consecutive records of `source`, ordered by
+/// `created_at`, over the optional time range. Segments longer than `max_segment_km` are ignored,
+/// to leave out GPS glitches.
+async fn get_distance_stats(State(app): State<App>, Query(query): Query<DistanceQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /stats/distance <- {:?}", query);
+
+    // Validate the parameters
+    if let Err(e) = sanitise_string(&query.source) {
+        return ApiError::from(e).into_response();
+    }
+    if query.max_segment_km.is_some_and(|max| !max.is_finite() || max <= 0.0) {
+        return ApiError::InvalidParameter("max_segment_km must be positive".to_string()).into_response();
+    }
+    if let Err(e) = validate_time_range(query.from, query.to) {
+        return e.into_response();
+    }
+
+    // Fetch the points from the database
+    let result = sqlx::query!(
+        r#"
+        SELECT latitude, longitude FROM locations
+        WHERE 
+            source = ?1
+            AND (?2 IS NULL OR created_at >= ?2)
+            AND (?3 IS NULL OR created_at <= ?3)
+            AND deleted_at IS NULL
+        ORDER BY created_at ASC, id ASC
+        "#,
+        query.source,
+        query.from,
+        query.to,
+    )
+    .fetch_all(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(points) => {
+            let distance_km: f64 = points
+                .windows(2)
+                .map(|w| geo::haversine_km((w[0].latitude, w[0].longitude), (w[1].latitude, w[1].longitude)))
+                .filter(|&d| query.max_segment_km.is_none_or(|max| d <= max))
+                .sum();
+            tracing::info!("{} km over {} points: {:?}", distance_km, points.len(), query);
+            Json(json!({ "distance_km": distance_km, "points": points.len() })).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot fetch records: {:?}", e);
+            ApiError::Database("No records fetched").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /simplified` endpoint.
+///
+/// It will return the track of `source` over the optional time range, ordered by `created_at` and
+/// simplified with the Ramer–Douglas–Peucker algorithm: every dropped record lies within
+/// `tolerance_m` metres of the returned track. At most `MAX_RESULTS` records are simplified, and
+/// `X-Result-Truncated` tells when there were more.
+async fn get_simplified(State(app): State<App>, Query(query): Query<SimplifyQuery>) -> impl IntoResponse {
+    // Log the request
+    tracing::info!("GET /simplified <- {:?}", query);
+
+    // Validate the parameters
+    if let Err(e) = sanitise_string(&query.source) {
+        return ApiError::from(e).into_response();
+    }
+    let tolerance_m = query.tolerance_m.unwrap_or(DEFAULT_SIMPLIFY_TOLERANCE_M);
+    if !tolerance_m.is_finite() || tolerance_m < 0.0 {
+        return ApiError::InvalidParameter("tolerance_m must not be negative".to_string()).into_response();
+    }
+    if let Err(e) = validate_time_range(query.from, query.to) {
+        return e.into_response();
+    }
+
+    // Fetch the records from the database, with one more to tell whether there are more
+    let fetch_limit = app.max_results + 1;
+    let result = sqlx::query_as!(
+        DbLocData,
+        r#"
+        SELECT * FROM locations
+        WHERE 
+            source = ?1
+            AND (?2 IS NULL OR created_at >= ?2)
+            AND (?3 IS NULL OR created_at <= ?3)
+            AND deleted_at IS NULL
+        ORDER BY created_at ASC, id ASC
+        LIMIT ?4
+        "#,
+        query.source,
+        query.from,
+        query.to,
+        fetch_limit,
+    )
+    .fetch_all(&app.database_pool)
+    .await;
+
+    // Verify the result and return the appropriate response
+    match result {
+        Ok(mut data) => {
+            let truncated = truncate_results(&mut data, app.max_results);
+            let kept = match data.first() {
+                Some(origin) => {
+                    let origin = (origin.latitude, origin.longitude);
+                    let points: Vec<(f64, f64)> = data
+                        .iter()
+                        .map(|loc| geo::project_m(origin, (loc.latitude, loc.longitude)))
+                        .collect();
+                    simplify::rdp(&points, tolerance_m)
+                }
+                None => Vec::new(),
+            };
+            tracing::info!("{} of {} records kept: {:?}", kept.len(), data.len(), query);
+            let simplified: Vec<&DbLocData> = kept.into_iter().map(|i| &data[i]).collect();
+            (truncated, Json(simplified)).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Cannot fetch records: {:?}", e);
+            ApiError::Database("No records fetched").into_response()
+        }
+    }
+}
+
+/// Handler for `GET /metrics` endpoint.
+///
+/// It will render the metrics in the Prometheus text format, for scraping.
+async fn get_metrics(State(app): State<App>) -> impl IntoResponse {
+    app.metrics.render()
+}
+
+/// Helper function to build the CORS layer from a comma-separated list of allowed origins.
+///
+/// `*` allows any origin, and an empty list denies all cross-origin requests.
+fn cors_layer(allowed_origins: &str) -> Result<CorsLayer> {
+    let origins: Vec<&str> = allowed_origins
+        .split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .collect();
+    let allow_origin = if origins.contains(&"*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .into_iter()
+            .map(|o| HeaderValue::from_str(o).with_context(|| format!("Invalid CORS origin {:?}", o)))
+            .collect::<Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::CONTENT_ENCODING,
+            header::IF_NONE_MATCH,
+            HeaderName::from_static("x-api-key"),
+            IDEMPOTENCY_KEY,
+        ])
+        .expose_headers([header::ETAG, X_PAGE_SIZE, X_RESULT_TRUNCATED]))
+}
+
+/// Helper function to normalise the path prefix the API is mounted under.
+///
+/// Trailing slashes are dropped, so `/api/` mounts at `/api`, and an empty prefix or `/` mounts at
+/// the root.
+fn base_path(prefix: &str) -> Result<String> {
+    let prefix = prefix.trim().trim_end_matches('/');
+    if prefix.is_empty() {
+        return Ok(String::new());
+    }
+    if !prefix.starts_with('/') || prefix.contains([':', '*', '?', '#']) {
+        anyhow::bail!("Invalid BASE_PATH {:?}: expected a path such as /api", prefix);
+    }
+    Ok(prefix.to_string())
+}
+
+/// Helper function to create the tracing span of a request.
+///
+/// The span carries the request id, so every log line emitted while handling the request can be
+/// correlated with it.
+fn make_request_span(request: &Request) -> Span {
+    let request_id = request.headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id,
+    )
+}
+
+/// Build the router serving the whole API over `state`.
+///
+/// Write endpoints are guarded by the API key, and request bodies are limited to `max_body_bytes`,
+/// or `max_batch_body_bytes` for batches and imports. Those may be gzipped, in which case the limits
+/// apply to the decompressed bodies. Trailing slashes are ignored, so `/count/` is `/count`.
+///
+/// Handlers taking longer than `request_timeout` to respond are aborted with `408 Request Timeout`.
+/// Only the wait for the response head is bounded, so streams such as `GET /stream`, `GET /ws` and
+/// NDJSON, which respond at once and then send their body for as long as needed, are not cut.
+fn router(
+    state: App,
+    cors: CorsLayer,
+    max_body_bytes: usize,
+    max_batch_body_bytes: usize,
+    request_timeout: Duration,
+) -> NormalizePath<Router> {
+    let write_routes = Router::new()
+        .route("/", post(post_location).layer(DefaultBodyLimit::max(max_body_bytes)))
+        .route("/batch", post(post_batch).layer(DefaultBodyLimit::max(max_batch_body_bytes)))
+        .route("/import", post(post_import).layer(DefaultBodyLimit::max(max_batch_body_bytes)))
+        .route("/locations/:id", put(put_location))
+        .route("/locations/:id", delete(delete_location))
+        .route("/locations", delete(delete_locations))
+        .route("/sources/:source", delete(delete_source))
+        .route("/sources/:source/rename", post(rename_source))
+        .route("/purge", delete(purge_deleted))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_api_key))
+        .route_layer(middleware::from_fn_with_state(state.clone(), reject_writes))
+        .layer(RequestDecompressionLayer::new());
+    let debug_routes = Router::new()
+        .route("/debug/config", get(get_debug_config))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_api_key));
+    let routes = Router::new()
+        .route("/", get(get_all_locations))
+        .route("/nearby", get(get_nearby_locations))
+        .route("/latest", get(get_latest_locations))
+        .route("/stream", get(get_stream))
+        .route("/ws", get(get_ws))
+        .route("/health", get(get_health))
+        .route("/version", get(get_version))
+        .route("/export.gpx", get(get_export_gpx))
+        .route("/export.kml", get(get_export_kml))
+        .route("/sources", get(get_sources))
+        .route("/sources/status", get(get_sources_status))
+        .route("/count", get(get_count))
+        .route("/bounds", get(get_bounds))
+        .route("/query", post(post_query))
+        .route("/stats/daily", get(get_daily_stats))
+        .route("/stats/distance", get(get_distance_stats))
+        .route("/simplified", get(get_simplified))
+        .route("/heatmap", get(get_heatmap))
+        .route("/render.png", get(get_render_png))
+        .route("/locations/:id", get(get_location_by_id))
+        .route("/locations/:id/nearby", get(get_nearby_record))
+        .route("/metrics", get(get_metrics))
+        .merge(write_routes)
+        .merge(debug_routes);
+    let routes = if state.base_path.is_empty() {
+        routes
+    } else {
+        Router::new().nest(&state.base_path, routes)
+    };
+    let routes = routes
+        .merge(openapi::docs_routes(&state.base_path))
+        .fallback(route_not_found)
+        .route_layer(middleware::from_fn(telemetry::track_metrics))
+        .layer(TimeoutLayer::new(request_timeout))
+        .layer(middleware::map_response(json_request_timeout))
+        .layer(middleware::map_response(json_method_not_allowed))
+        .layer(middleware::from_fn_with_state(state.clone(), rename_json_fields))
+        .layer(CompressionLayer::new())
+        .layer(cors)
+        .layer(TraceLayer::new_for_http().make_span_with(make_request_span))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .with_state(state);
+
+    // Trailing slashes are trimmed before routing, as layers of the router run after it
+    NormalizePath::trim_trailing_slash(routes)
+}
+
+/// Fallback handler for paths matching no route.
+async fn route_not_found(request: Request) -> impl IntoResponse {
+    tracing::info!("No route for {} {}", request.method(), request.uri());
+    ApiError::RouteNotFound
+}
+
+/// Middleware replacing the empty body of `405 Method Not Allowed` responses by a JSON error.
+///
+/// Axum answers them itself when a route exists for other methods, and keeps the `Allow` header.
+async fn json_method_not_allowed(response: Response) -> Response {
+    if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return response;
+    }
+    let mut json = ApiError::MethodNotAllowed.into_response();
+    if let Some(allow) = response.headers().get(header::ALLOW) {
+        json.headers_mut().insert(header::ALLOW, allow.clone());
+    }
+    json
+}
+
+/// Middleware replacing the empty body of `408 Request Timeout` responses by a JSON error.
+///
+/// They are answered by the timeout layer when a handler takes too long, which is then dropped.
+async fn json_request_timeout(response: Response) -> Response {
+    if response.status() != StatusCode::REQUEST_TIMEOUT {
+        return response;
+    }
+    ApiError::RequestTimeout.into_response()
+}
+
+/// Middleware renaming the fields of JSON responses to camelCase, when configured.
+///
+/// Responses are serialised in snake_case everywhere, so they are rewritten here as a whole rather
+/// than by every handler. Other formats, including GeoJSON and NDJSON, are left untouched.
+async fn rename_json_fields(State(app): State<App>, request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    let is_json = response.headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if app.field_case != FieldCase::Camel || !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let value = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).map(case::camel_case_keys),
+        Err(e) => {
+            tracing::error!("Cannot read response: {:?}", e);
+            return ApiError::Internal("Cannot read response").into_response();
+        }
+    };
+    match value {
+        Ok(value) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, axum::body::Body::from(value.to_string()))
+        }
+        Err(e) => {
+            tracing::error!("Cannot parse JSON response: {:?}", e);
+            ApiError::Internal("Cannot read response").into_response()
+        }
+    }
+}
+
+/// Future completing when the process is asked to stop, via Ctrl-C or SIGTERM on Unix.
+///
+/// It is used to shut the server down gracefully, letting in-flight requests finish.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Cannot install Ctrl-C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Cannot install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("Shutting down gracefully");
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Load environment variables
+    #[cfg(debug_assertions)]
+    dotenvy::dotenv().context("Cannot load .env file")?;
+
+    // Set up tracing
+    let log_format = env_or("LOG_FORMAT", LogFormat::default())?;
+    telemetry::init_tracing(log_format);
+
+    // Open database connection
+    let database_url = std::env::var("DATABASE_URL")
+        .context("DATABASE_URL is not set")?;
+    let max_connections = env_or("DB_MAX_CONNECTIONS", DEFAULT_DB_MAX_CONNECTIONS)?;
+    let acquire_timeout_secs = env_or("DB_ACQUIRE_TIMEOUT_SECS", DEFAULT_DB_ACQUIRE_TIMEOUT_SECS)?;
+    let busy_timeout_ms = env_or("DB_BUSY_TIMEOUT_MS", DEFAULT_DB_BUSY_TIMEOUT_MS)?;
+    let slow_query_ms = env_or("SLOW_QUERY_MS", DEFAULT_SLOW_QUERY_MS)?;
+    let connect_attempts = env_or("DB_CONNECT_ATTEMPTS", DEFAULT_DB_CONNECT_ATTEMPTS)?.max(1);
+    let connect_retry_ms = env_or("DB_CONNECT_RETRY_MS", DEFAULT_DB_CONNECT_RETRY_MS)?;
+    let create_if_missing = env_or("DB_CREATE_IF_MISSING", true)?;
+
+    // These pragmas are applied to every pooled connection. WAL lets reads proceed while a write
+    // is in progress, and the busy timeout makes writers wait for each other instead of failing.
+    // Every query is timed, and logged as a warning when slower than the threshold. It is logged
+    // within the span of the request, which carries the endpoint and filters. The database file is
+    // created when missing unless disabled, so that first runs need no setup.
+    let connect_options = SqliteConnectOptions::from_str(&database_url)
+        .context("Invalid DATABASE_URL")?
+        .create_if_missing(create_if_missing)
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(Duration::from_millis(busy_timeout_ms))
+        .foreign_keys(true)
+        .log_statements(LevelFilter::Debug)
+        .log_slow_statements(LevelFilter::Warn, Duration::from_millis(slow_query_ms));
+    let pool_options = SqlitePoolOptions::new()
+        .max_connections(max_connections)
+        .acquire_timeout(Duration::from_secs(acquire_timeout_secs));
+
+    // Tell whether the database file was created by connecting. In-memory databases never are.
+    let database_file = connect_options.get_filename().to_path_buf();
+    let existed = database_file.exists();
+
+    // The database may not be ready yet when started alongside it, so wait for it with exponential
+    // backoff before giving up
+    let mut delay = Duration::from_millis(connect_retry_ms);
+    let mut attempt = 1;
+    let database_pool = loop {
+        match pool_options.clone().connect_with(connect_options.clone()).await {
+            Ok(pool) => break pool,
+            Err(e) if attempt < connect_attempts => {
+                tracing::warn!(
+                    "Cannot connect to database (attempt {} of {}), retrying in {:?}: {}",
+                    attempt,
+                    connect_attempts,
+                    delay,
+                    e,
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e).context("Cannot connect to database"),
+        }
+    };
+    if !existed && database_file.exists() {
+        tracing::info!("Created database file {}", database_file.display());
+    }
+
+    // Run pending migrations
+    sqlx::migrate!()
+        .run(&database_pool)
+        .await
+        .context("Cannot run database migrations")?;
+
+    // Read the API key guarding write endpoints
+    let api_key = std::env::var("API_KEY")
+        .ok()
+        .filter(|k| !k.is_empty());
+    if api_key.is_none() {
+        tracing::warn!("API_KEY is not set, write endpoints are unauthenticated");
+    }
+
+    // Read the request body limits of write endpoints
+    let max_body_bytes = env_or("MAX_BODY_BYTES", DEFAULT_MAX_BODY_BYTES)?;
+    let max_batch_body_bytes = env_or("MAX_BATCH_BODY_BYTES", DEFAULT_MAX_BATCH_BODY_BYTES)?;
+    let max_batch_size = env_or("MAX_BATCH_SIZE", DEFAULT_MAX_BATCH_SIZE)?;
+
+    // Read how long handlers may take to respond
+    let request_timeout_secs = env_or("REQUEST_TIMEOUT_SECS", DEFAULT_REQUEST_TIMEOUT_SECS)?;
+    if request_timeout_secs == 0 {
+        anyhow::bail!("Invalid REQUEST_TIMEOUT_SECS 0: must be at least 1");
+    }
+    let request_timeout = Duration::from_secs(request_timeout_secs);
+
+    // Read the origins allowed to make cross-origin requests
+    let cors_allowed_origins = std::env::var("CORS_ALLOWED_ORIGINS")
+        .unwrap_or_default();
+    let cors = cors_layer(&cors_allowed_origins)?;
+
+    // Set up metrics
+    let metrics = telemetry::install()?;
+    let metrics_refresh_secs = env_or("METRICS_REFRESH_SECS", DEFAULT_METRICS_REFRESH_SECS)?;
+    tokio::spawn(telemetry::refresh_row_count(
+        database_pool.clone(),
+        metrics.clone(),
+        Duration::from_secs(metrics_refresh_secs),
+    ));
+
+    // Read the page sizes of `GET /`
+    let default_page_size = env_or("DEFAULT_PAGE_SIZE", DEFAULT_PAGE_SIZE)?;
+    let max_page_size = env_or("MAX_PAGE_SIZE", DEFAULT_MAX_PAGE_SIZE)?;
+    if default_page_size < 0 || max_page_size < 0 || default_page_size > max_page_size {
+        anyhow::bail!("DEFAULT_PAGE_SIZE and MAX_PAGE_SIZE must be non-negative, with the default not above the max");
+    }
+
+    // Read the cap on results of the endpoints without pagination
+    let max_results = env_or("MAX_RESULTS", DEFAULT_MAX_RESULTS)?;
+    if max_results < 0 {
+        anyhow::bail!("MAX_RESULTS must be non-negative");
+    }
+
+    // Set up rate limiting of writes
+    let rate_limit_per_minute = env_or("RATE_LIMIT_PER_MINUTE", DEFAULT_RATE_LIMIT_PER_MINUTE)?;
+    let rate_limit_burst = env_or("RATE_LIMIT_BURST", rate_limit_per_minute)?.max(1);
+    let rate_limiter = if rate_limit_per_minute > 0 {
+        let limiter = Arc::new(RateLimiter::new(rate_limit_per_minute, rate_limit_burst));
+        tokio::spawn(rate_limit::remove_idle(limiter.clone(), RATE_LIMIT_CLEANUP_PERIOD));
+        Some(limiter)
+    } else {
+        None
+    };
+
+    // Set up deduplication of repeated readings
+    let dedup_distance_m = env_or("DEDUP_DISTANCE_M", DEFAULT_DEDUP_DISTANCE_M)?;
+    let dedup_window_secs = env_or("DEDUP_WINDOW_SECS", DEFAULT_DEDUP_WINDOW_SECS)?;
+    let dedup = (dedup_distance_m > 0.0).then(|| Dedup {
+        distance_km: dedup_distance_m / 1000.0,
+        window: TimeDelta::seconds(dedup_window_secs),
+    });
+
+    // Delete old records periodically when a retention is configured
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let retention_days = env_or("RETENTION_DAYS", 0)?;
+    let retention_interval_secs = env_or("RETENTION_INTERVAL_SECS", DEFAULT_RETENTION_INTERVAL_SECS)?;
+    let retention_task = if retention_days > 0 {
+        let period = Duration::from_secs(retention_interval_secs);
+        tracing::info!("Deleting records older than {} days every {:?}", retention_days, period);
+        Some(tokio::spawn(purge_periodically(
+            database_pool.clone(),
+            TimeDelta::days(retention_days),
+            period,
+            shutdown_rx,
+        )))
+    } else {
+        None
+    };
+
+    // Set up the caches of slow queries
+    let cache_ttl_secs = env_or("CACHE_TTL_SECS", DEFAULT_CACHE_TTL_SECS)?;
+    let cache_ttl = Duration::from_secs(cache_ttl_secs);
+
+    // Read how long idempotency keys are remembered
+    let idempotency_ttl_secs = env_or("IDEMPOTENCY_TTL_SECS", DEFAULT_IDEMPOTENCY_TTL_SECS)?;
+    let idempotency_ttl = TimeDelta::seconds(idempotency_ttl_secs);
+
+    // Set up reverse geocoding when a service is configured
+    let geocoder = match std::env::var("GEOCODER_URL") {
+        Ok(url) if !url.is_empty() => {
+            let timeout = Duration::from_millis(env_or("GEOCODER_TIMEOUT_MS", DEFAULT_GEOCODER_TIMEOUT_MS)?);
+            Some(Arc::new(Geocoder::new(&url, timeout)?))
+        }
+        _ => None,
+    };
+
+    // Read the number of decimal places coordinates are rounded to, if any
+    let coord_precision = match std::env::var("COORD_PRECISION") {
+        Ok(v) if !v.is_empty() => {
+            let precision = v.parse::<u32>()
+                .ok()
+                .filter(|&p| p <= MAX_COORD_PRECISION)
+                .with_context(|| format!("Invalid COORD_PRECISION {:?}: expected 0 to {}", v, MAX_COORD_PRECISION))?;
+            Some(precision)
+        }
+        _ => None,
+    };
+
+    // Read how long sources may stay silent before being stale
+    let stale_after_secs = env_or("STALE_AFTER_SECS", DEFAULT_STALE_AFTER_SECS)?;
+    let stale_after = TimeDelta::seconds(stale_after_secs);
+
+    // Read the accuracy thresholds of the quality of records
+    let quality = QualityThresholds {
+        good_m: env_or("QUALITY_GOOD_M", DEFAULT_QUALITY_GOOD_M)?,
+        fair_m: env_or("QUALITY_FAIR_M", DEFAULT_QUALITY_FAIR_M)?,
+    };
+    if !(0.0..=quality.fair_m).contains(&quality.good_m) {
+        anyhow::bail!("QUALITY_GOOD_M and QUALITY_FAIR_M must be non-negative, with good not above fair");
+    }
+
+    // Read the naming of the fields of JSON responses
+    let field_case = env_or("JSON_FIELD_CASE", FieldCase::default())?;
+
+    // Read the source of records sent without one
+    let default_source = match std::env::var("DEFAULT_SOURCE") {
+        Ok(v) if !v.trim().is_empty() => {
+            sanitise_string(&v).with_context(|| format!("Invalid DEFAULT_SOURCE {:?}", v))?;
+            Some(v.trim().to_string())
+        }
+        _ => None,
+    };
+
+    // Read whether writes are forbidden
+    let read_only = env_or("READ_ONLY", false)?;
+    if read_only {
+        tracing::info!("Read-only mode: writes are forbidden");
+    }
+
+    // Read the path prefix the API is mounted under
+    let base_path = base_path(&std::env::var("BASE_PATH").unwrap_or_default())?;
+
+    // Read the address to serve on
+    let bind_addr = std::env::var("BIND_ADDR")
+        .unwrap_or_else(|_| "0.0.0.0".to_string());
+    let port = std::env::var("PORT")
+        .context("PORT is not set")?;
+    let ip: IpAddr = bind_addr.parse()
+        .with_context(|| format!("Invalid BIND_ADDR {:?}", bind_addr))?;
+    let port: u16 = port.parse()
+        .with_context(|| format!("Invalid PORT {:?}", port))?;
+    let addr = SocketAddr::new(ip, port);
+    let cert_path = std::env::var("TLS_CERT_PATH").ok().filter(|p| !p.is_empty());
+    let key_path = std::env::var("TLS_KEY_PATH").ok().filter(|p| !p.is_empty());
+
+    // Keep the effective configuration for `GET /debug/config` when debug endpoints are enabled
+    let debug_endpoints = env_or("DEBUG_ENDPOINTS", false)?;
+    let debug_config = debug_endpoints.then(|| {
+        Arc::new(EffectiveConfig {
+            bind_addr: ip.to_string(),
+            port,
+            tls: cert_path.is_some() && key_path.is_some(),
+            base_path: base_path.clone(),
+            log_format: format!("{:?}", log_format).to_lowercase(),
+            api_key_set: api_key.is_some(),
+            read_only,
+            cors_allowed_origins: cors_allowed_origins.clone(),
+            db_max_connections: max_connections,
+            db_acquire_timeout_secs: acquire_timeout_secs,
+            db_busy_timeout_ms: busy_timeout_ms,
+            db_connect_attempts: connect_attempts,
+            db_connect_retry_ms: connect_retry_ms,
+            db_create_if_missing: create_if_missing,
+            slow_query_ms,
+            max_body_bytes,
+            max_batch_body_bytes,
+            max_batch_size,
+            request_timeout_secs,
+            default_page_size,
+            max_page_size,
+            max_results,
+            metrics_refresh_secs,
+            rate_limit_per_minute,
+            rate_limit_burst,
+            dedup_distance_m,
+            dedup_window_secs,
+            retention_days,
+            retention_interval_secs,
+            cache_ttl_secs,
+            idempotency_ttl_secs,
+            geocoder: geocoder.is_some(),
+            coord_precision,
+            stale_after_secs,
+            quality_good_m: quality.good_m,
+            quality_fair_m: quality.fair_m,
+            json_field_case: format!("{:?}", field_case).to_lowercase(),
+            default_source: default_source.clone(),
+        })
+    });
+    if debug_endpoints && api_key.is_none() {
+        tracing::warn!("DEBUG_ENDPOINTS is enabled without API_KEY, the configuration is public");
+    }
+
+    // Initialise application
+    let state = App {
+        database_pool,
+        api_key,
+        metrics,
+        default_page_size,
+        max_page_size,
+        max_results,
+        updates: broadcast::channel(UPDATES_CAPACITY).0,
+        rate_limiter,
+        dedup,
+        sources_cache: Arc::new(TtlCache::new(cache_ttl)),
+        count_cache: Arc::new(TtlCache::new(cache_ttl)),
+        idempotency_ttl,
+        geocoder,
+        base_path,
+        read_only,
+        coord_precision,
+        stale_after,
+        field_case,
+        default_source,
+        max_batch_size,
+        quality,
+        debug_config,
+    };
+    let app = router(state, cors, max_body_bytes, max_batch_body_bytes, request_timeout);
+
+    // Serve HTTPS directly when a certificate is configured
+    let tls_config = match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let config = RustlsConfig::from_pem_file(&cert_path, &key_path)
+                .await
+                .with_context(|| format!("Cannot load TLS certificate {:?} and key {:?}", cert_path, key_path))?;
+            Some(config)
+        }
+        (None, None) => None,
+        _ => anyhow::bail!("TLS_CERT_PATH and TLS_KEY_PATH must be set together"),
+    };
+    let scheme = if tls_config.is_some() { "https" } else { "http" };
+    tracing::info!("Starting server on {}://{}", scheme, addr);
+
+    // Stop serving gracefully when asked to, then let the background tasks know
+    let shutdown = async move {
+        shutdown_signal().await;
+        // Sending only fails when no background task is listening, which is fine
+        let _ = shutdown_tx.send(true);
+    };
+
+    match tls_config {
+        Some(config) => {
+            let handle = Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown.await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            axum_server::bind_rustls(addr, config)
+                .handle(handle)
+                .serve(ServiceExt::<Request>::into_make_service(app))
+                .await
+                .context("Cannot start server")?;
+        }
+        None => {
+            let listener = TcpListener::bind(&addr)
+                .await
+                .context("Cannot bind to port")?;
+            axum::serve(listener, ServiceExt::<Request>::into_make_service(app))
+                .with_graceful_shutdown(shutdown)
+                .await
+                .context("Cannot start server")?;
+        }
+    }
+
+    // Wait for the background tasks to stop
+    if let Some(task) = retention_task {
+        task.await.context("Retention task failed")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitise_string_accepts_identifiers() {
+        for s in ["phone", "phone_01", "my phone", "phone-01.home", "tracker:42", "A.b-C:d_e 9"] {
+            assert_eq!(sanitise_string(s), Ok(()), "{:?} should be accepted", s);
+        }
+    }
+
+    #[test]
+    fn sanitise_string_rejects_unsafe_characters() {
+        for s in ["a;b", "a'b", "a\"b", "a/b", "a\\b", "a%b", "a\nb", "a\tb", "a*", "a=b"] {
+            assert_eq!(sanitise_string(s), Err(SourceError::DisallowedCharacters), "{:?} should be rejected", s);
+        }
+    }
+
+    #[test]
+    fn sanitise_string_rejects_sql_metacharacters_and_unicode() {
+        for s in ["phone'; DROP TABLE locations; --", "a OR 1=1", "a)", "a,b", "téléphone", "手机", "phone📱", "a\u{200b}b"] {
+            assert!(sanitise_string(s).is_err(), "{:?} should be rejected", s);
+        }
+    }
+
+    #[test]
+    fn sanitise_string_ignores_surrounding_whitespace() {
+        assert_eq!(sanitise_string(" phone "), Ok(()));
+        assert_eq!(sanitise_string("\tphone\n"), Ok(()));
+        assert_eq!(sanitise_string(&format!(" {} ", "a".repeat(MAX_SOURCE_LEN))), Ok(()));
+    }
+
+    #[test]
+    fn sources_are_trimmed_when_deserialised() {
+        let data: PostLocData = serde_json::from_str(r#"{"source": " phone ", "latitude": 0, "longitude": 0}"#).unwrap();
+        assert_eq!(data.source, "phone");
+
+        let SourceList(sources) = "a, b ,c".parse().unwrap();
+        assert_eq!(sources, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn sanitise_string_rejects_empty_and_long_strings() {
+        assert_eq!(sanitise_string(""), Err(SourceError::Empty));
+        assert_eq!(sanitise_string("   "), Err(SourceError::Empty));
+        assert_eq!(sanitise_string(&"a".repeat(MAX_SOURCE_LEN)), Ok(()));
+        assert_eq!(
+            sanitise_string(&"a".repeat(MAX_SOURCE_LEN + 1)),
+            Err(SourceError::TooLong { max: MAX_SOURCE_LEN }),
+        );
+    }
+
+    #[test]
+    fn output_format_from_accept_weighs_media_types() {
+        let format = OutputFormat::from_accept;
+        assert_eq!(format("text/csv"), Some(OutputFormat::Csv));
+        assert_eq!(format("text/csv;q=0.5, application/geo+json"), Some(OutputFormat::GeoJson));
+        assert_eq!(format("application/x-ndjson, text/csv"), Some(OutputFormat::Ndjson));
+        assert_eq!(format("text/csv;q=0, application/json;q=0.1"), Some(OutputFormat::Json));
+        assert_eq!(format("text/html, */*"), None);
+    }
+
+    #[test]
+    fn base_path_is_normalised() {
+        assert_eq!(base_path("").unwrap(), "");
+        assert_eq!(base_path("/").unwrap(), "");
+        assert_eq!(base_path("/api/v1/").unwrap(), "/api/v1");
+        assert!(base_path("api").is_err());
+        assert!(base_path("/:id").is_err());
+    }
 }
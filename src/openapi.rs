@@ -0,0 +1,86 @@
+//! OpenAPI description of the API.
+//!
+//! The specification is generated from the annotated handlers and types, served at `/openapi.json`
+//! and browsable with Swagger UI at `/docs`. It only covers `GET /` and `POST /` so far, the other
+//! endpoints being described by the doc comments of their handlers.
+
+use axum::http::{header, HeaderValue};
+use axum::middleware;
+use axum::response::Response;
+use axum::Router;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::openapi::Server;
+use utoipa::{Modify, OpenApi, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::{App, DbLocData, OutputFormat, PostBody, PostLocData, SortOrder};
+
+/// OpenAPI specification of the API.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "ntb_location", description = "Store and query location readings of devices."),
+    paths(crate::get_all_locations, crate::post_location),
+    components(schemas(DbLocData, PostBody, PostLocData, SortOrder, OutputFormat, ErrorBody, CreatedBody)),
+    modifiers(&ApiKeyScheme),
+)]
+pub struct ApiDoc;
+
+/// Error returned to the client, as serialised by `ApiError`.
+#[derive(ToSchema)]
+#[allow(dead_code)] // Only describes the body of errors in the specification
+pub struct ErrorBody {
+    /// Human-readable message.
+    error: String,
+    /// Stable, machine-readable identifier.
+    code: String,
+    /// Path of the offending field, for malformed JSON bodies.
+    field: Option<String>,
+}
+
+/// Record or array of records inserted by `POST /`, depending on the body of the request.
+#[derive(ToSchema)]
+#[serde(untagged)]
+#[allow(dead_code)] // Only describes the body of responses in the specification
+pub enum CreatedBody {
+    One(DbLocData),
+    Many(Vec<DbLocData>),
+}
+
+/// Declares the `X-API-Key` header guarding the write endpoints.
+struct ApiKeyScheme;
+
+impl Modify for ApiKeyScheme {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-API-Key"))),
+        );
+    }
+}
+
+/// Build the routes of the Swagger UI at `/docs` and of the specification at `/openapi.json`, both
+/// under `base_path`.
+///
+/// When the API is mounted under a prefix, it is declared as the server so the paths resolve.
+pub fn docs_routes(base_path: &str) -> Router<App> {
+    let mut spec = ApiDoc::openapi();
+    if !base_path.is_empty() {
+        spec.servers = Some(vec![Server::new(base_path)]);
+    }
+    let docs = format!("{}/docs", base_path);
+    let swagger_ui = SwaggerUi::new(docs.clone()).url(format!("{}/openapi.json", base_path), spec);
+
+    // Swagger UI redirects `/docs` to `/docs/`, whose trailing slash is trimmed before routing, so
+    // send clients to the page itself instead
+    let index = HeaderValue::try_from(format!("{}/index.html", docs)).expect("base path is valid in a header");
+    Router::from(swagger_ui).layer(middleware::map_response(move |mut response: Response| {
+        let index = index.clone();
+        async move {
+            if response.status().is_redirection() {
+                response.headers_mut().insert(header::LOCATION, index);
+            }
+            response
+        }
+    }))
+}
@@ -0,0 +1,86 @@
+//! Quality of location records, bucketed from their accuracy.
+//!
+//! Clients get a `good`, `fair` or `poor` flag instead of having to know what a given accuracy in
+//! metres means. The buckets are derived when querying, so changing the thresholds applies to every
+//! record already stored.
+
+use std::str::FromStr;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Quality of a location record, from the worst to the best.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    /// The accuracy of the record is unknown.
+    Unknown,
+    Poor,
+    Fair,
+    Good,
+}
+
+impl FromStr for Quality {
+    type Err = String;
+
+    /// Parse a minimum quality, which can only be a known one.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "good" => Ok(Quality::Good),
+            "fair" => Ok(Quality::Fair),
+            "poor" => Ok(Quality::Poor),
+            _ => Err(format!("Invalid quality {:?}: must be \"good\", \"fair\" or \"poor\"", s)),
+        }
+    }
+}
+
+/// Greatest accuracies, in metres, of the `good` and `fair` buckets. Records less accurate than
+/// `fair_m` are `poor`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityThresholds {
+    pub good_m: f64,
+    pub fair_m: f64,
+}
+
+impl QualityThresholds {
+    /// Quality of a record of the given accuracy.
+    pub fn quality(&self, accuracy: Option<f64>) -> Quality {
+        match accuracy {
+            None => Quality::Unknown,
+            Some(accuracy) if accuracy <= self.good_m => Quality::Good,
+            Some(accuracy) if accuracy <= self.fair_m => Quality::Fair,
+            Some(_) => Quality::Poor,
+        }
+    }
+
+    /// Greatest accuracy of the records of at least the given quality, or `None` when any known
+    /// accuracy will do.
+    pub fn max_accuracy(&self, min_quality: Quality) -> Option<f64> {
+        match min_quality {
+            Quality::Good => Some(self.good_m),
+            Quality::Fair => Some(self.fair_m),
+            Quality::Poor | Quality::Unknown => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quality_is_bucketed_by_accuracy() {
+        let thresholds = QualityThresholds { good_m: 20.0, fair_m: 100.0 };
+        assert_eq!(thresholds.quality(None), Quality::Unknown);
+        assert_eq!(thresholds.quality(Some(5.0)), Quality::Good);
+        assert_eq!(thresholds.quality(Some(20.0)), Quality::Good);
+        assert_eq!(thresholds.quality(Some(50.0)), Quality::Fair);
+        assert_eq!(thresholds.quality(Some(500.0)), Quality::Poor);
+    }
+
+    #[test]
+    fn unknown_is_not_a_minimum_quality() {
+        assert_eq!("fair".parse::<Quality>(), Ok(Quality::Fair));
+        assert!("unknown".parse::<Quality>().is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! Per-source rate limiting of writes.
+//!
+//! Each source gets a token bucket holding up to `burst` tokens, refilled at `per_minute` tokens per
+//! minute. Every insert takes one token, and inserts finding the bucket empty are rejected.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket of a single source.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Rate limiter keeping one token bucket per source.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    burst: f64,
+    per_second: f64,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter allowing `per_minute` inserts per minute and source, in bursts of up to
+    /// `burst` inserts.
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        RateLimiter {
+            buckets: Mutex::new(HashMap::new()),
+            burst: burst as f64,
+            per_second: per_minute as f64 / 60.0,
+        }
+    }
+
+    /// Take a token for `source`, returning how long to wait before retrying when there is none.
+    pub fn check(&self, source: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+        let bucket = buckets.entry(source.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated_at: now,
+        });
+
+        // Refill the tokens accumulated since the last insert
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second))
+        }
+    }
+
+    /// Forget the sources whose bucket would be full again, as they behave as if new.
+    pub fn remove_idle(&self) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+        buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+            bucket.tokens + elapsed * self.per_second < self.burst
+        });
+    }
+}
+
+/// Periodically forget idle sources, so that the rate limiter doesn't grow without bound.
+///
+/// It runs forever, so it should be spawned as a background task.
+pub async fn remove_idle(limiter: Arc<RateLimiter>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        limiter.remove_idle();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_rejects_once_burst_is_used_up() {
+        let limiter = RateLimiter::new(60, 2);
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        let retry_after = limiter.check("a").unwrap_err();
+        assert!(retry_after <= Duration::from_secs(1));
+
+        // Other sources have their own bucket
+        assert!(limiter.check("b").is_ok());
+    }
+}
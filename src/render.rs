@@ -0,0 +1,210 @@
+//! Rendering of location records as PNG images, for sharing tracks without a client app.
+//!
+//! Points are projected with Web Mercator, like web maps, and the view is fitted to their bounds.
+//! There is no map background: fetching tiles would make every image depend on a tile server, so
+//! tracks are drawn over a plain grid instead.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use crate::DbLocData;
+
+/// Media type of PNG images.
+pub const PNG_CONTENT_TYPE: &str = "image/png";
+
+/// Margin left around the points, in pixels.
+const MARGIN: f64 = 16.0;
+
+/// Spacing of the grid lines, in pixels.
+const GRID_SPACING: usize = 32;
+
+/// Color of the background.
+const BACKGROUND: Rgb = [248, 248, 244];
+
+/// Color of the grid lines.
+const GRID: Rgb = [228, 228, 222];
+
+/// Colors of the tracks, given to sources in order of appearance.
+const PALETTE: [Rgb; 6] = [
+    [31, 119, 180],
+    [214, 39, 40],
+    [44, 160, 44],
+    [255, 127, 14],
+    [148, 103, 189],
+    [23, 190, 207],
+];
+
+/// Color of a pixel, as red, green and blue.
+type Rgb = [u8; 3];
+
+/// Image being drawn, as rows of RGB pixels.
+struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    /// Create a canvas filled with the background and its grid.
+    fn new(width: usize, height: usize) -> Self {
+        let mut canvas = Canvas { width, height, pixels: BACKGROUND.repeat(width * height) };
+        for y in 0..height {
+            for x in 0..width {
+                if x % GRID_SPACING == 0 || y % GRID_SPACING == 0 {
+                    canvas.set(x as i64, y as i64, GRID);
+                }
+            }
+        }
+        canvas
+    }
+
+    /// Set the color of a pixel, ignoring those outside of the canvas.
+    fn set(&mut self, x: i64, y: i64, color: Rgb) {
+        if (0..self.width as i64).contains(&x) && (0..self.height as i64).contains(&y) {
+            let i = (y as usize * self.width + x as usize) * 3;
+            self.pixels[i..i + 3].copy_from_slice(&color);
+        }
+    }
+
+    /// Draw a disc of `radius` pixels centred on a point.
+    fn dot(&mut self, (x, y): (f64, f64), radius: i64, color: Rgb) {
+        let (cx, cy) = (x.round() as i64, y.round() as i64);
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                if dx * dx + dy * dy <= radius * radius {
+                    self.set(cx + dx, cy + dy, color);
+                }
+            }
+        }
+    }
+
+    /// Draw a line two pixels thick between two points.
+    fn line(&mut self, (x0, y0): (f64, f64), (x1, y1): (f64, f64), color: Rgb) {
+        let steps = (x1 - x0).abs().max((y1 - y0).abs()).ceil().max(1.0) as usize;
+        for step in 0..=steps {
+            let t = step as f64 / steps as f64;
+            self.dot((x0 + (x1 - x0) * t, y0 + (y1 - y0) * t), 1, color);
+        }
+    }
+
+    /// Encode the canvas as a PNG image.
+    fn encode(&self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        Ok(out)
+    }
+}
+
+/// Project a point with Web Mercator, onto a plane where `y` grows northwards.
+///
+/// Latitudes are clamped to the limits of web maps, as the poles are infinitely far away.
+fn mercator(lat: f64, lon: f64) -> (f64, f64) {
+    let lat = lat.clamp(-85.0511, 85.0511).to_radians();
+    (lon.to_radians(), (PI / 4.0 + lat / 2.0).tan().ln())
+}
+
+/// Render location records as a PNG image of `width` by `height` pixels.
+///
+/// Records should already be sorted by `created_at`. Consecutive records of each source are joined
+/// into a track of its own color, and the last record of each source is marked with a larger dot.
+/// The view is zoomed to fit every record, keeping the aspect ratio of the projection.
+pub fn png(data: &[DbLocData], width: u32, height: u32) -> Result<Vec<u8>, png::EncodingError> {
+    let mut canvas = Canvas::new(width as usize, height as usize);
+
+    // Fit the bounds of the points within the margins, centred
+    let projected: Vec<(f64, f64)> = data.iter().map(|loc| mercator(loc.latitude, loc.longitude)).collect();
+    let (min_x, max_x, min_y, max_y) = projected.iter().fold(
+        (f64::INFINITY, f64::NEG_INFINITY, f64::INFINITY, f64::NEG_INFINITY),
+        |(min_x, max_x, min_y, max_y), &(x, y)| (min_x.min(x), max_x.max(x), min_y.min(y), max_y.max(y)),
+    );
+    let inner_width = (width as f64 - 2.0 * MARGIN).max(1.0);
+    let inner_height = (height as f64 - 2.0 * MARGIN).max(1.0);
+    let scale = (inner_width / (max_x - min_x)).min(inner_height / (max_y - min_y));
+    // A single spot has no extent, so any scale centres it
+    let scale = if scale.is_finite() { scale } else { 1.0 };
+    let (center_x, center_y) = ((min_x + max_x) / 2.0, (min_y + max_y) / 2.0);
+    let to_pixel = |(x, y): (f64, f64)| {
+        (width as f64 / 2.0 + (x - center_x) * scale, height as f64 / 2.0 - (y - center_y) * scale)
+    };
+
+    // Draw the tracks of the sources
+    let mut colors: HashMap<&str, Rgb> = HashMap::new();
+    let mut last: HashMap<&str, (f64, f64)> = HashMap::new();
+    for (loc, &point) in data.iter().zip(&projected) {
+        let next_color = PALETTE[colors.len() % PALETTE.len()];
+        let color = *colors.entry(loc.source.as_str()).or_insert(next_color);
+        let pixel = to_pixel(point);
+        match last.insert(loc.source.as_str(), pixel) {
+            Some(previous) => canvas.line(previous, pixel, color),
+            None => canvas.dot(pixel, 1, color),
+        }
+    }
+    for (source, &pixel) in &last {
+        canvas.dot(pixel, 4, colors[source]);
+    }
+
+    canvas.encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+
+    fn record(source: &str, latitude: f64, longitude: f64) -> DbLocData {
+        DbLocData {
+            id: 0,
+            source: source.to_string(),
+            latitude,
+            longitude,
+            altitude: None,
+            accuracy: None,
+            speed: None,
+            battery: None,
+            provider: None,
+            tags: None,
+            created_at: NaiveDateTime::default(),
+            deleted_at: None,
+        }
+    }
+
+    /// Decode a PNG image into its dimensions and RGB pixels.
+    fn decode(image: &[u8]) -> (u32, u32, Vec<u8>) {
+        let mut reader = png::Decoder::new(std::io::Cursor::new(image)).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        (info.width, info.height, pixels)
+    }
+
+    #[test]
+    fn png_draws_tracks_fitted_to_the_image() {
+        let data = [record("a", 48.85, 2.35), record("a", 48.86, 2.36), record("b", 48.855, 2.355)];
+        let (width, height, pixels) = decode(&png(&data, 120, 80).unwrap());
+        assert_eq!((width, height), (120, 80));
+
+        let pixel = |x: u32, y: u32| {
+            let i = ((y * width + x) * 3) as usize;
+            [pixels[i], pixels[i + 1], pixels[i + 2]]
+        };
+        // The track of the first source joins the corners of the fitted view, through its centre,
+        // where the second source sits
+        assert_eq!(pixel(60, 40), PALETTE[1]);
+        assert_ne!(pixel(2, 2), PALETTE[0]);
+        assert!(pixels.chunks(3).any(|p| p == PALETTE[0]));
+    }
+
+    #[test]
+    fn png_handles_no_points_and_a_single_point() {
+        let (_, _, pixels) = decode(&png(&[], 10, 10).unwrap());
+        assert_eq!(&pixels[33..36], &BACKGROUND);
+
+        let (width, _, pixels) = decode(&png(&[record("a", 0.0, 0.0)], 11, 11).unwrap());
+        let centre = ((5 * width + 5) * 3) as usize;
+        assert_eq!(&pixels[centre..centre + 3], &PALETTE[0]);
+    }
+}
@@ -0,0 +1,83 @@
+//! Simplification of tracks with the Ramer–Douglas–Peucker algorithm.
+//!
+//! Points are planar `(x, y)` tuples, so geographic points should be projected first, for instance
+//! with [`crate::geo::project_m`].
+
+/// Indices of the points kept when simplifying the polyline `points` with tolerance `epsilon`.
+///
+/// Every dropped point lies within `epsilon` of the simplified polyline. The first and last points
+/// are always kept, and the indices are returned in increasing order.
+pub fn rdp(points: &[(f64, f64)], epsilon: f64) -> Vec<usize> {
+    if points.len() < 3 {
+        return (0..points.len()).collect();
+    }
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    // Work through the segments with an explicit stack, as long tracks could overflow the call stack
+    let mut segments = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = segments.pop() {
+        let farthest = (start + 1..end)
+            .map(|i| (i, distance_to_segment(points[i], points[start], points[end])))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((i, distance)) = farthest {
+            if distance > epsilon {
+                keep[i] = true;
+                segments.push((start, i));
+                segments.push((i, end));
+            }
+        }
+    }
+
+    keep.iter()
+        .enumerate()
+        .filter_map(|(i, &kept)| kept.then_some(i))
+        .collect()
+}
+
+/// Distance from `p` to the segment between `a` and `b`.
+fn distance_to_segment(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length_squared = dx * dx + dy * dy;
+    if length_squared == 0.0 {
+        return (p.0 - a.0).hypot(p.1 - a.1);
+    }
+
+    // Clamp the projection of `p` onto the line to the segment itself
+    let t = (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / length_squared).clamp(0.0, 1.0);
+    (p.0 - (a.0 + t * dx)).hypot(p.1 - (a.1 + t * dy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rdp_keeps_short_polylines() {
+        assert_eq!(rdp(&[], 1.0), Vec::<usize>::new());
+        assert_eq!(rdp(&[(0.0, 0.0)], 1.0), vec![0]);
+        assert_eq!(rdp(&[(0.0, 0.0), (1.0, 1.0)], 1.0), vec![0, 1]);
+    }
+
+    #[test]
+    fn rdp_drops_collinear_points() {
+        let points = [(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (3.0, 0.0)];
+        assert_eq!(rdp(&points, 0.1), vec![0, 3]);
+        assert_eq!(rdp(&points, 0.0), vec![0, 3]);
+    }
+
+    #[test]
+    fn rdp_keeps_corners_beyond_tolerance() {
+        let points = [(0.0, 0.0), (1.0, 0.1), (2.0, -0.1), (3.0, 5.0), (4.0, 6.0), (5.0, 7.0), (6.0, 8.1), (7.0, 9.0)];
+        assert_eq!(rdp(&points, 1.0), vec![0, 2, 3, 7]);
+    }
+
+    #[test]
+    fn distance_to_segment_clamps_to_endpoints() {
+        assert_eq!(distance_to_segment((0.0, 1.0), (0.0, 0.0), (2.0, 0.0)), 1.0);
+        assert_eq!(distance_to_segment((-3.0, 4.0), (0.0, 0.0), (2.0, 0.0)), 5.0);
+        assert_eq!(distance_to_segment((3.0, 4.0), (0.0, 0.0), (0.0, 0.0)), 5.0);
+    }
+}
@@ -0,0 +1,128 @@
+//! Logging and Prometheus metrics.
+//!
+//! Logs are written to stdout in the [`LogFormat`] chosen at startup. Metrics expose request counts
+//! and latencies, recorded by [`track_metrics`], and the total number of location records, refreshed
+//! periodically by [`refresh_row_count`].
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use axum::extract::{MatchedPath, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use sqlx::SqlitePool;
+use thiserror::Error;
+use tracing_subscriber::{prelude::*, fmt, EnvFilter, Layer};
+
+/// Format of the logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable, one line per event.
+    #[default]
+    Full,
+    /// Human-readable, spread over several lines per event.
+    Pretty,
+    /// Like `Full`, but shorter.
+    Compact,
+    /// One JSON object per line, with the fields of the event and its span as keys.
+    Json,
+}
+
+/// Error returned when parsing an unknown [`LogFormat`].
+#[derive(Debug, Error)]
+#[error("must be \"full\", \"pretty\", \"compact\" or \"json\"")]
+pub struct InvalidLogFormat;
+
+impl FromStr for LogFormat {
+    type Err = InvalidLogFormat;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(LogFormat::Full),
+            "pretty" => Ok(LogFormat::Pretty),
+            "compact" => Ok(LogFormat::Compact),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(InvalidLogFormat),
+        }
+    }
+}
+
+/// Install the global tracing subscriber, filtered by `RUST_LOG`.
+///
+/// In JSON, the fields of the current span, such as the request id, are included under `span`.
+pub fn init_tracing(format: LogFormat) {
+    let layer = match format {
+        LogFormat::Full => fmt::layer().boxed(),
+        LogFormat::Pretty => fmt::layer().pretty().boxed(),
+        LogFormat::Compact => fmt::layer().compact().boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(EnvFilter::from_default_env())
+        .init();
+}
+
+/// Buckets of the request latency histogram, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Install the global Prometheus recorder, returning a handle to render the metrics.
+pub fn install() -> Result<PrometheusHandle> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full("http_request_duration_seconds".to_string()),
+            LATENCY_BUCKETS,
+        )
+        .context("Cannot set latency histogram buckets")?
+        .install_recorder()
+        .context("Cannot install metrics recorder")
+}
+
+/// Middleware recording the count and latency of requests.
+///
+/// Requests are labelled by their route pattern rather than the raw path, so that paths such as
+/// `/locations/:id` don't produce one series per id.
+pub async fn track_metrics(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let path = request.extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    let labels = [("method", method), ("path", path), ("status", status)];
+    metrics::counter!("http_requests_total", &labels).increment(1);
+    metrics::histogram!("http_request_duration_seconds", &labels).record(start.elapsed().as_secs_f64());
+
+    response
+}
+
+/// Periodically refresh the gauge holding the total number of location records, leaving out the
+/// deleted ones like `GET /count`.
+///
+/// It also runs the exporter's upkeep, which would otherwise only happen when metrics are scraped.
+/// It runs forever, so it should be spawned as a background task.
+pub async fn refresh_row_count(pool: SqlitePool, handle: PrometheusHandle, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        handle.run_upkeep();
+        let result = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!: i64" FROM locations WHERE deleted_at IS NULL"#)
+            .fetch_one(&pool)
+            .await;
+        match result {
+            Ok(count) => metrics::gauge!("locations_total").set(count as f64),
+            Err(e) => tracing::error!("Cannot count records for metrics: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Timestamp parsing and formatting.
+//!
+//! Timestamps are stored in the database as naive date-times in UTC. Clients always receive them as
+//! RFC 3339 with a `Z` suffix, and may send them with any offset, which is converted to UTC.
+//!
+//! The `serialize`/`deserialize` pair makes this module usable with `#[serde(with = "timestamp")]`.
+
+use chrono::{DateTime, NaiveDateTime, SecondsFormat};
+use serde::{de, Deserialize, Deserializer, Serializer};
+
+/// Description of the accepted timestamp formats, for error messages.
+pub const ACCEPTED_FORMATS: &str =
+    "RFC 3339 such as 2024-05-01T10:00:00Z or 2024-05-01T12:00:00+02:00, or 2024-05-01T10:00:00 for UTC";
+
+/// Parse a timestamp sent by a client into naive UTC.
+///
+/// Timestamps without an offset are taken to be in UTC already, as they were before offsets were
+/// supported.
+pub fn parse(s: &str) -> Result<NaiveDateTime, String> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.naive_utc());
+    }
+    s.parse::<NaiveDateTime>()
+        .map_err(|_| format!("Invalid timestamp {:?}: expected {}", s, ACCEPTED_FORMATS))
+}
+
+/// Format a naive UTC timestamp as RFC 3339 with a `Z` suffix.
+pub fn format(t: &NaiveDateTime) -> String {
+    t.and_utc().to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+pub fn serialize<S: Serializer>(t: &NaiveDateTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(t))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveDateTime, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse(&s).map_err(de::Error::custom)
+}
+
+/// Same as the parent module for optional timestamps, usable with `#[serde(with = "timestamp::option")]`.
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(t: &Option<NaiveDateTime>, serializer: S) -> Result<S::Ok, S::Error> {
+        match t {
+            Some(t) => super::serialize(t, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<NaiveDateTime>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(s) => parse(&s).map(Some).map_err(de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_converts_offsets_to_utc() {
+        let expected = "2024-05-01T10:00:00".parse::<NaiveDateTime>().unwrap();
+        assert_eq!(parse("2024-05-01T10:00:00Z"), Ok(expected));
+        assert_eq!(parse("2024-05-01T12:00:00+02:00"), Ok(expected));
+        assert_eq!(parse("2024-05-01T10:00:00"), Ok(expected));
+        assert!(parse("2024-05-01").is_err());
+        assert!(parse("yesterday").is_err());
+    }
+
+    #[test]
+    fn format_uses_z_suffix() {
+        let t = "2024-05-01T10:00:00".parse::<NaiveDateTime>().unwrap();
+        assert_eq!(format(&t), "2024-05-01T10:00:00Z");
+    }
+}